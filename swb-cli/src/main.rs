@@ -1,12 +1,23 @@
-use std::{net::{Ipv4Addr, SocketAddr}, num::ParseIntError, str::FromStr, sync::{Arc, Mutex}};
+use std::{net::{Ipv4Addr, SocketAddr}, num::ParseIntError, path::PathBuf, str::FromStr, sync::{Arc, Mutex}, time::Duration};
 
 use clap::{Args, Parser, Subcommand};
-use futures::future;
+use futures::{future, stream, StreamExt};
 use tracing::Level;
 use self_update::cargo_crate_version;
 use url::{Host, Url};
 
-use swb::SwbError;
+use swb::{
+    broadcast::{console_connection::ConsoleOptions, dolphin_connection::DolphinOptions},
+    overlay::OverlayFeed,
+    BroadcastDelay,
+    ForwardOptions,
+    PlaybackControl,
+    SpectateOptions,
+    SlippiLifecycleEvent,
+    SlippiLifecycleReceiver,
+    SlippiSource,
+    SwbError
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about)]
@@ -27,7 +38,10 @@ struct Cli {
 #[derive(Subcommand, Debug)]
 enum Commands {
     Broadcast(Broadcast),
-    Spectate(Spectate)
+    Spectate(Spectate),
+    Record(Record),
+    Serve(Serve),
+    Relay(Relay)
 }
 
 /// Stream one or multiple Slippi instances to SpectatorMode.
@@ -40,26 +54,134 @@ struct Broadcast {
     /// Slippi sources to forward data from, in the format schema://host:port.
     /// schema may be "console" or "dolphin", and defaults to "console" if
     /// unspecified. Multiple sources may be given.
+    ///
+    /// Console sources accept the query parameters cursor, client_token and
+    /// realtime, for example console://192.168.1.5?realtime=true. Dolphin
+    /// sources accept reconnect, to keep waiting for Dolphin to come back if
+    /// it is closed, for example dolphin://127.0.0.1:51441?reconnect=true.
+    ///
+    /// A .slp replay file may also be broadcast as if it were being played
+    /// live, in the format file:///path/to/replay.slp. Playback speed can be
+    /// changed with a multiplier from 0.01 to 100, for example
+    /// file:///path/to/replay.slp?speed=2.
     #[arg(short, long, default_value = "dolphin://127.0.0.1:51441")]
     source: Vec<String>,
+
+    /// Also save every game broadcast to .slp files in this directory, in a
+    /// subdirectory per stream ID.
+    #[arg(long)]
+    archive_dir: Option<PathBuf>,
+
+    /// How to name archived games, with the same placeholders as
+    /// `replay_name_template` in swb's settings.json, for example
+    /// "{date}/{players}_{stage}".
+    #[arg(long, value_name = "TEMPLATE", requires = "archive_dir")]
+    archive_name_template: Option<String>,
+
+    /// Serve the state of the games being broadcast as JSON for stream
+    /// overlays on this address, at http://address/state. A WebSocket
+    /// connection to the same URL receives every update. With multiple
+    /// sources, add ?stream_id=N to choose one.
+    #[arg(long)]
+    overlay_address: Option<SocketAddr>,
+
+    /// Delay the broadcast by this many seconds, for example so that players
+    /// at a tournament cannot watch their opponents' stream.
+    #[arg(long, value_name = "SECONDS")]
+    delay: Option<u64>,
+
+    /// When quitting with a delay, discard the data still being held back
+    /// instead of sending it once its delay is up.
+    #[arg(long, requires = "delay")]
+    discard_delayed: bool,
 }
 
-/// Mirror a stream in Playback Dolphin. This can consume a stream either from
-/// SpectatorMode, or from an arbitrary source.
+/// Mirror one or more streams in Playback Dolphin. This can consume streams
+/// either from SpectatorMode, or from arbitrary sources. Each stream is
+/// mirrored in its own Playback Dolphin, unless `--follow` is given.
 ///
 /// If an arbitrary source is provided, swb expects it to be a WebSocket server
 /// which (1) sends the entire .slp replay up to the current point upon
 /// connection, and (2) sends Slippi events as they come in after connection.
 ///
 /// Messages must be sent from the server in binary mode as unwrapped Slippi
-/// events. One message may contain multiple Slippi events, but a Slippi event
-/// must not be split between multiple messages.
+/// events. Messages do not need to be split on event boundaries.
 #[derive(Args, Debug)]
 struct Spectate {
+    /// The stream identifiers. Each can either be the stream ID from
+    /// SpectatorMode, or a full WebSocket URL to the source.
+    #[arg(value_parser = infer_stream_url, required = true)]
+    stream_url: Vec<String>,
+
+    /// Watch all streams in a single Playback Dolphin, which switches to
+    /// whichever stream most recently started a game. To follow a bridge,
+    /// list each of its stream IDs.
+    #[arg(long)]
+    follow: bool,
+
+    /// Serve the state of the game being watched as JSON for stream overlays
+    /// on this address, at http://address/state. A WebSocket connection to
+    /// the same URL receives every update.
+    #[arg(long)]
+    overlay_address: Option<SocketAddr>,
+
+    /// Watch this many seconds behind live, for smoother playback on an
+    /// unstable connection. While watching, type `p` and Enter to pause, `r`
+    /// to resume, `l` to jump to live, or a number of seconds to change the
+    /// delay.
+    #[arg(long, value_name = "SECONDS")]
+    delay: Option<u64>,
+}
+
+/// Save a stream to .slp files without watching it. This does not need
+/// Playback Dolphin, a Melee ISO or Slippi Launcher to be set up, so it can run
+/// on a headless server.
+///
+/// Streams are given in the same format as for `swb spectate`.
+#[derive(Args, Debug)]
+struct Record {
     /// The stream identifier. This can either be the stream ID from
     /// SpectatorMode, or a full WebSocket URL to the source.
     #[arg(value_parser = infer_stream_url)]
-    stream_url: String
+    stream_url: String,
+
+    /// The directory to save replays in.
+    #[arg(short, long, default_value = ".")]
+    output_dir: PathBuf,
+
+    /// How to name saved games, with the same placeholders as
+    /// `replay_name_template` in swb's settings.json, for example
+    /// "{date}/{players}_{stage}".
+    #[arg(long, value_name = "TEMPLATE")]
+    name_template: Option<String>,
+}
+
+/// Run a local server in place of SpectatorMode, for example to broadcast and
+/// spectate within a LAN without internet access.
+///
+/// Bridges connect to ws://host:port/bridge_socket/websocket, which can be
+/// passed to `swb broadcast --dest`. Viewers connect to
+/// ws://host:port/viewer_socket/websocket?stream_id=N&full_replay=true, which
+/// can be passed to `swb spectate`. A bridge which loses its connection has a
+/// minute to reconnect and resume its streams.
+#[derive(Args, Debug)]
+struct Serve {
+    /// The address to listen for connections on.
+    #[arg(short, long, default_value = "0.0.0.0:4000")]
+    address: SocketAddr,
+}
+
+/// Serve one Slippi instance directly to viewers on the local network, without
+/// SpectatorMode. Viewers can connect with `swb spectate ws://host:port`.
+#[derive(Args, Debug)]
+struct Relay {
+    /// The Slippi source to relay, in the same format as for `swb broadcast`.
+    #[arg(short, long, default_value = "dolphin://127.0.0.1:51441")]
+    source: String,
+
+    /// The address to listen for viewers on.
+    #[arg(short, long, default_value = "0.0.0.0:4000")]
+    address: SocketAddr,
 }
 
 fn infer_stream_url(stream_param: &str) -> Result<String, ParseIntError> {
@@ -115,10 +237,23 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             let result =
                 match &args.command {
                     Commands::Broadcast(b) => {
-                        connect_and_forward_packets_until_completion(&b.source, b.dest.as_str()).await
+                        connect_and_forward_packets_until_completion(b).await
                     }
                     Commands::Spectate(s) => {
-                        swb::mirror_to_dolphin(s.stream_url.as_str()).await
+                        spectate(s).await
+                    }
+                    Commands::Record(r) => {
+                        // Stopping early still saves the game in progress
+                        tokio::select! {
+                            result = swb::record(r.stream_url.as_str(), r.output_dir.clone(), r.name_template.as_deref()) => result,
+                            _ = tokio::signal::ctrl_c() => Ok(())
+                        }
+                    }
+                    Commands::Serve(s) => {
+                        swb::serve(s.address).await.map_err(SwbError::from)
+                    }
+                    Commands::Relay(r) => {
+                        relay_until_completion(&r.source, r.address).await
                     }
                 };
 
@@ -132,40 +267,91 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-async fn connect_and_forward_packets_until_completion(sources: &Vec<String>, dest: &str) -> Result<(), SwbError>  {
+async fn spectate(args: &Spectate) -> Result<(), SwbError> {
+    let playback = args.delay.map(|delay| PlaybackControl::new(Duration::from_secs(delay)));
+
+    if let Some(playback) = &playback {
+        tokio::spawn(read_playback_commands(playback.clone()));
+    }
+
+    let options = SpectateOptions { overlay: start_overlay(args.overlay_address).await?, playback };
+
+    if args.follow {
+        swb::follow_in_dolphin(&args.stream_url, options).await
+    } else {
+        swb::mirror_to_dolphin(&args.stream_url, options).await
+    }
+}
+
+/// Control playback with commands typed into the terminal.
+async fn read_playback_commands(playback: PlaybackControl) {
+    println!("Type p to pause, r to resume, l to jump to live, or a number of seconds to change the delay, then press Enter.");
+
+    // Reading stdin blocks, and tokio's stdin would hold up the runtime
+    // shutting down until a line is entered. A plain thread is simply left
+    // behind when the process exits.
+    let (line_tx, mut line_rx) = tokio::sync::mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else { break };
+            if line_tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    while let Some(line) = line_rx.recv().await {
+        match line.trim() {
+            "p" => playback.pause(),
+            "r" => playback.resume(),
+            "l" => playback.jump_to_live(),
+            other => {
+                match u64::from_str(other) {
+                    Ok(delay) => playback.set_delay(Duration::from_secs(delay)),
+                    Err(_) => {
+                        println!("Unknown command: {}", other);
+                        continue;
+                    }
+                }
+            }
+        }
+
+        println!("Now {:.0} seconds behind live.", playback.lag().as_secs_f64());
+    }
+}
+
+/// Start the overlay server if an address is given.
+async fn start_overlay(overlay_address: Option<SocketAddr>) -> Result<Option<OverlayFeed>, SwbError> {
+    match overlay_address {
+        Some(address) => swb::overlay::start(address).await.map(Some).map_err(SwbError::OverlayServerError),
+        None => Ok(None)
+    }
+}
+
+async fn connect_and_forward_packets_until_completion(args: &Broadcast) -> Result<(), SwbError>  {
+    let sources = &args.source;
+    let dest = args.dest.as_str();
+    let forward_options = ForwardOptions {
+        archive_directory: args.archive_dir.clone(),
+        archive_name_template: args.archive_name_template.clone(),
+        delay: args.delay.map(|delay| BroadcastDelay { delay: Duration::from_secs(delay), flush_on_end: !args.discard_delayed }),
+        overlay: start_overlay(args.overlay_address).await?,
+    };
+
     // Initiate connections.
     let mut slippi_conns = vec![];
     let mut slippi_interrupts = vec![];
+    let mut slippi_lifecycles = vec![];
     let sources_owned = sources.clone();
     let mut already_interrupted = false;
 
     for source_string in sources_owned {
-        let string_to_parse =
-            if !source_string.contains("://") {
-                format!("{}{}", "console://", source_string)
-            } else {
-                source_string
-            };
-        let parsed_url = Url::parse(string_to_parse.as_str())?;
-
-        let scheme = parsed_url.scheme();
-        let host = parsed_url.host().unwrap_or(Host::Ipv4(Ipv4Addr::from_str("127.0.0.1").unwrap()));
-        let port = parsed_url.port().unwrap_or(51441);
-
-        tracing::debug!("using url scheme: {:?}, host: {:?}, port: {:?}", scheme, host, port);
-
-        let socket_addr_string = format!("{}:{}", host, port);
-        let source_addr = SocketAddr::from_str(socket_addr_string.as_str())?;
-        let is_console =
-            match scheme {
-                "console" => Ok(true),
-                "dolphin" => Ok(false),
-                other_scheme => Err(SwbError::UnknownSourceScheme(other_scheme.to_string()))
-            }?;
-
-        let (slippi_conn, slippi_interrupt) = swb::connect_to_slippi(source_addr, is_console).await;
+        let source = parse_source(source_string)?;
+
+        let (slippi_conn, slippi_interrupt, _connection_info, slippi_lifecycle) = swb::connect_to_slippi(source).await?;
         slippi_conns.push(slippi_conn);
         slippi_interrupts.push(slippi_interrupt);
+        slippi_lifecycles.push(slippi_lifecycle);
     }
 
     let slippi_interrupts = Arc::new(Mutex::new(slippi_interrupts));
@@ -188,7 +374,7 @@ async fn connect_and_forward_packets_until_completion(sources: &Vec<String>, des
 
     // Set up the futures to await.
     // Each individual future will attempt to gracefully disconnect the other.
-    let dolphin_to_sm = swb::forward_streams(slippi_conns, bridge_info.stream_ids, sm_client);
+    let dolphin_to_sm = swb::forward_streams(slippi_conns, bridge_info.stream_ids, sm_client, forward_options);
 
     let sm_connection_future = async {
         let sm_client_result = sm_connection_monitor.wait_for_close().await;
@@ -197,8 +383,10 @@ async fn connect_and_forward_packets_until_completion(sources: &Vec<String>, des
         sm_client_result
     };
 
-    // Run until both futures complete.
-    let (slippi_to_sm_result, sm_client_result) = future::join(dolphin_to_sm, sm_connection_future).await;
+    let lifecycle_future = log_lifecycle_events(sources, slippi_lifecycles);
+
+    // Run until all futures complete.
+    let (slippi_to_sm_result, sm_client_result, _) = future::join3(dolphin_to_sm, sm_connection_future, lifecycle_future).await;
 
     slippi_to_sm_result?;
     tracing::debug!("Slippi stream finished successfully");
@@ -207,3 +395,154 @@ async fn connect_and_forward_packets_until_completion(sources: &Vec<String>, des
 
     Ok(())
 }
+
+async fn relay_until_completion(source_string: &str, address: SocketAddr) -> Result<(), SwbError> {
+    let source = parse_source(source_string.to_string())?;
+    let (slippi_conn, mut slippi_interrupt, _connection_info, slippi_lifecycle) = swb::connect_to_slippi(source).await?;
+    let mut already_interrupted = false;
+
+    ctrlc::set_handler(move || {
+        if already_interrupted {
+            std::process::exit(2);
+        } else {
+            tracing::info!("Shutting down gracefully... press Ctrl + C again to force exit.");
+            already_interrupted = true;
+            slippi_interrupt();
+        }
+    })
+    .unwrap();
+
+    let sources = [source_string.to_string()];
+    let lifecycle_future = log_lifecycle_events(&sources, vec![slippi_lifecycle]);
+
+    let (relay_result, _) = future::join(swb::relay(slippi_conn, address), lifecycle_future).await;
+    relay_result?;
+    tracing::debug!("Slippi stream finished successfully");
+
+    Ok(())
+}
+
+/// Log game and connection events from each source until all sources finish.
+async fn log_lifecycle_events(sources: &[String], slippi_lifecycles: Vec<SlippiLifecycleReceiver>) {
+    let mut games_played = 0;
+
+    let indexed_lifecycles = slippi_lifecycles
+        .into_iter()
+        .enumerate()
+        .map(|(i, lifecycle)| lifecycle.map(move |event| (i, event)));
+
+    stream::select_all(indexed_lifecycles)
+        .for_each(|(i, event)| {
+            let source = &sources[i];
+
+            match event {
+                SlippiLifecycleEvent::GameStarted => tracing::info!("Game started on {}", source),
+                SlippiLifecycleEvent::GameEnded => {
+                    games_played += 1;
+                    tracing::info!("Game ended on {} ({} games played)", source, games_played);
+                }
+                SlippiLifecycleEvent::Disconnected => tracing::warn!("Lost connection to {}, reconnecting...", source),
+                SlippiLifecycleEvent::Reconnected => tracing::info!("Reconnected to {}", source),
+            }
+
+            future::ready(())
+        })
+        .await
+}
+
+/// Parse a Slippi source in the format schema://host:port.
+#[allow(clippy::result_large_err)]
+fn parse_source(source_string: String) -> Result<SlippiSource, SwbError> {
+    let string_to_parse =
+        if !source_string.contains("://") {
+            format!("{}{}", "console://", source_string)
+        } else {
+            source_string
+        };
+    let parsed_url = Url::parse(string_to_parse.as_str())?;
+
+    let scheme = parsed_url.scheme();
+
+    match scheme {
+        "console" | "dolphin" => {
+            let host = parsed_url.host().unwrap_or(Host::Ipv4(Ipv4Addr::from_str("127.0.0.1").unwrap()));
+            let port = parsed_url.port().unwrap_or(51441);
+
+            tracing::debug!("using url scheme: {:?}, host: {:?}, port: {:?}", scheme, host, port);
+
+            let socket_addr_string = format!("{}:{}", host, port);
+            let source_addr = SocketAddr::from_str(socket_addr_string.as_str())?;
+
+            if scheme == "console" {
+                let options = parse_console_options(&parsed_url).map_err(SwbError::InvalidSource)?;
+                Ok(SlippiSource::Console(source_addr, options))
+            } else {
+                let options = parse_dolphin_options(&parsed_url).map_err(SwbError::InvalidSource)?;
+                Ok(SlippiSource::Dolphin(source_addr, options))
+            }
+        }
+        "file" => parse_file_source(&parsed_url).map_err(SwbError::InvalidSource),
+        other_scheme => Err(SwbError::UnknownSourceScheme(other_scheme.to_string()))
+    }
+}
+
+fn parse_console_options(parsed_url: &Url) -> Result<ConsoleOptions, String> {
+    let mut options = ConsoleOptions::default();
+
+    for (key, value) in parsed_url.query_pairs() {
+        match key.as_ref() {
+            "cursor" => {
+                options.cursor = Some(u64::from_str(&value).map_err(|_| format!("invalid cursor: {}", value))?);
+            }
+            "client_token" => {
+                options.client_token = Some(u32::from_str(&value).map_err(|_| format!("invalid client token: {}", value))?);
+            }
+            "realtime" => {
+                options.realtime = bool::from_str(&value).map_err(|_| format!("invalid realtime value: {}", value))?;
+            }
+            other_key => return Err(format!("unknown console option: {}", other_key))
+        }
+    }
+
+    tracing::debug!("using console options: {:?}", options);
+
+    Ok(options)
+}
+
+fn parse_dolphin_options(parsed_url: &Url) -> Result<DolphinOptions, String> {
+    let mut options = DolphinOptions::default();
+
+    for (key, value) in parsed_url.query_pairs() {
+        match key.as_ref() {
+            "reconnect" => {
+                options.reconnect = bool::from_str(&value).map_err(|_| format!("invalid reconnect value: {}", value))?;
+            }
+            other_key => return Err(format!("unknown Dolphin option: {}", other_key))
+        }
+    }
+
+    tracing::debug!("using Dolphin options: {:?}", options);
+
+    Ok(options)
+}
+
+fn parse_file_source(parsed_url: &Url) -> Result<SlippiSource, String> {
+    let path = parsed_url.to_file_path()
+        .map_err(|_| format!("invalid file path: {}", parsed_url))?;
+
+    let speed =
+        match parsed_url.query_pairs().find(|(key, _)| key == "speed") {
+            Some((_, value)) => {
+                match f64::from_str(&value) {
+                    // The speed is clamped when the file is opened
+                    Ok(speed) if speed.is_finite() && speed > 0.0 => speed,
+                    _ => return Err(format!("invalid replay speed: {}", value))
+                }
+            }
+            None => 1.0
+        };
+
+    tracing::debug!("using replay file: {:?}, speed: {:?}", path, speed);
+
+    Ok(SlippiSource::File { path, speed })
+}
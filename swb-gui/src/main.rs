@@ -10,6 +10,7 @@ use iced::Alignment::Center;
 use iced::futures::{future, Stream};
 use iced::futures::channel::mpsc;
 
+use swb::SlippiConnectionInfo;
 use swb::spectator_mode_client::BridgeInfo;
 
 const DEFAULT_HOST: &str = "wss://spectatormode.tv";
@@ -45,9 +46,10 @@ enum Message {
 /// Events sent from the swb lib, received by the client application.
 #[derive(Debug, Clone)]
 enum BroadcastEvent {
-    SlippiConnected,
-    BroadcastStarted(BridgeInfo, mpsc::Sender<SwbLibSignal>),
-    BroadcastStopped
+    SlippiConnected(SlippiConnectionInfo),
+    BroadcastStarted(BridgeInfo, SlippiConnectionInfo, mpsc::Sender<SwbLibSignal>),
+    BroadcastStopped,
+    BroadcastFailed(String)
 }
 
 #[derive(Debug, Clone)]
@@ -66,21 +68,23 @@ enum SwbLibSignal {
 enum State {
     Standby(String), // Entered stream ID
     SlippiConnecting,
-    SpectatorModeConnecting,
-    Broadcasting(BridgeInfo, mpsc::Sender<SwbLibSignal>),
+    SpectatorModeConnecting(SlippiConnectionInfo),
+    Broadcasting(BridgeInfo, SlippiConnectionInfo, mpsc::Sender<SwbLibSignal>),
     Spectating(u32)
 }
 
 struct SwbGui {
     state: State,
-    sm_host: String
+    sm_host: String,
+    error: Option<String>
 }
 
 impl SwbGui {
     fn new(sm_host: &str) -> Self {
         Self {
             state: State::Standby(String::new()),
-            sm_host: sm_host.to_string()
+            sm_host: sm_host.to_string(),
+            error: None
         }
     }
 
@@ -89,15 +93,17 @@ impl SwbGui {
 
         match message {
             Message::Broadcast => {
+                self.error = None;
                 self.state = State::SlippiConnecting;
             },
 
             Message::Spectate(stream_id) => {
+                self.error = None;
                 self.state = State::Spectating(stream_id);
             }
 
             Message::Stop => {
-                if let State::Broadcasting(_, interrupt) = &mut self.state {
+                if let State::Broadcasting(_, _, interrupt) = &mut self.state {
                     interrupt.try_send(SwbLibSignal::StopRequest).unwrap();
                 } else {
                     // If not fully broadcasting, stop request won't do anything.
@@ -110,17 +116,22 @@ impl SwbGui {
                 tracing::debug!("Received swb event: {:?}", event);
 
                 match event {
-                    BroadcastEvent::SlippiConnected => {
-                        self.state = State::SpectatorModeConnecting;
+                    BroadcastEvent::SlippiConnected(connection_info) => {
+                        self.state = State::SpectatorModeConnecting(connection_info);
                     }
 
-                    BroadcastEvent::BroadcastStarted(bridge_info, interrupt_sender) => {
-                        self.state = State::Broadcasting(bridge_info, interrupt_sender);
+                    BroadcastEvent::BroadcastStarted(bridge_info, connection_info, interrupt_sender) => {
+                        self.state = State::Broadcasting(bridge_info, connection_info, interrupt_sender);
                     }
 
                     BroadcastEvent::BroadcastStopped => {
                         self.state = State::Standby(String::new());
                     }
+
+                    BroadcastEvent::BroadcastFailed(error) => {
+                        self.error = Some(error);
+                        self.state = State::Standby(String::new());
+                    }
                 }
             }
 
@@ -158,18 +169,19 @@ impl SwbGui {
                             None
                         }
                     )
-                ].spacing(10)
+                ].spacing(10),
+                text(self.error.clone().unwrap_or_default()).style(text::danger)
             ],
             State::SlippiConnecting => column![
                 text(format!("Connecting to Slippi...")).size(20),
                 button("Stop broadcast").on_press(Message::Stop)
             ],
-            State::SpectatorModeConnecting => column![
-                text(format!("Slippi connected; connecting to SpectatorMode...")).size(20),
+            State::SpectatorModeConnecting(connection_info) => column![
+                text(format!("Connected to {}; connecting to SpectatorMode...", connection_info)).size(20),
                 button("Stop broadcast").on_press(Message::Stop)
             ],
-            State::Broadcasting(bridge_info, _interrupt) => column![
-                text(format!("Broadcasting with stream ID {}", bridge_info.stream_ids[0])).size(20),
+            State::Broadcasting(bridge_info, connection_info, _interrupt) => column![
+                text(format!("Broadcasting {} with stream ID {}", connection_info, bridge_info.stream_ids[0])).size(20),
                 button("Stop broadcast").on_press(Message::Stop)
             ],
             State::Spectating(stream_id) => column![
@@ -208,18 +220,35 @@ fn broadcast(sm_host: String) -> impl Stream<Item = BroadcastEvent> {
         let dest = format!("{sm_host}/bridge_socket/websocket");
         let source_addr = SocketAddr::from_str(source).unwrap();
 
-        let (slippi_conn, slippi_interrupt) = swb::connect_to_slippi(source_addr, false).await;
-        output.send(BroadcastEvent::SlippiConnected).await.unwrap();
-        let (sm_client, mut sm_connection_monitor, bridge_info) = swb::initiate_spectatormode_connection(dest.as_str(), 1).await.unwrap();
+        let (slippi_conn, slippi_interrupt, connection_info, _slippi_lifecycle) =
+            match swb::connect_to_slippi(swb::SlippiSource::Dolphin(source_addr, Default::default())).await {
+                Ok(connection) => connection,
+                Err(error) => {
+                    tracing::error!("Failed to connect to Slippi: {}", error);
+                    output.send(BroadcastEvent::BroadcastFailed(error.to_string())).await.unwrap();
+                    return;
+                }
+            };
+        output.send(BroadcastEvent::SlippiConnected(connection_info.clone())).await.unwrap();
+
+        let (sm_client, mut sm_connection_monitor, bridge_info) =
+            match swb::initiate_spectatormode_connection(dest.as_str(), 1).await {
+                Ok(connection) => connection,
+                Err(error) => {
+                    tracing::error!("Failed to connect to SpectatorMode: {}", error);
+                    output.send(BroadcastEvent::BroadcastFailed(error.to_string())).await.unwrap();
+                    return;
+                }
+            };
 
         // This is the sender/receiver for the main thread to tell things to this sub-thread
         // Specifically, to initiate a disconnect request
         let (sender, mut receiver) = mpsc::channel(100);
-        output.send(BroadcastEvent::BroadcastStarted(bridge_info.clone(), sender)).await.unwrap();
+        output.send(BroadcastEvent::BroadcastStarted(bridge_info.clone(), connection_info, sender)).await.unwrap();
 
         // Set up the futures to await.
         // Each individual future will attempt to gracefully disconnect the other.
-        let dolphin_to_sm = swb::forward_streams(vec![slippi_conn], bridge_info.stream_ids, sm_client);
+        let dolphin_to_sm = swb::forward_streams(vec![slippi_conn], bridge_info.stream_ids, sm_client, Default::default());
 
         let slippi_interrupt = Arc::new(Mutex::new(slippi_interrupt));
         let slippi_interrupt_clone = Arc::clone(&slippi_interrupt);
@@ -258,7 +287,7 @@ fn spectate(sm_host: String, stream_id: u32) -> impl Stream<Item = SpectateEvent
     stream::channel(100, move |mut output| async move {
         output.send(SpectateEvent::Started(stream_id)).await.unwrap();
 
-        let mirror_result = swb::mirror_to_dolphin(format!("{sm_host}/websocket?stream_id={}&full_replay=true", stream_id).as_str()).await;
+        let mirror_result = swb::mirror_to_dolphin(&[format!("{sm_host}/websocket?stream_id={}&full_replay=true", stream_id)], Default::default()).await;
 
         if let Err(error) = mirror_result {
             tracing::error!("Dolphin mirror exited with error: {:?}", error);
@@ -1,11 +1,21 @@
-use std::pin::Pin;
+use std::{
+    collections::{HashMap, VecDeque},
+    io::Write,
+    path::PathBuf,
+    pin::Pin,
+    time::Duration
+};
 
+use tokio::time::{sleep_until, Instant};
 use tokio_stream::StreamMap;
 use futures::{stream::StreamExt, Stream, Future};
+use async_stream::stream;
 use ezsockets::Bytes;
 
 use crate::{
     common::SlippiDataStream,
+    overlay::{GameStateTracker, OverlayFeed},
+    spectate::{replay_name::DEFAULT_REPLAY_NAME_TEMPLATE, slp_file_writer::SlpFileWriter},
     spectator_mode_client::{SpectatorModeClient, SpectatorModeClientError}
 };
 
@@ -28,6 +38,115 @@ fn merge_slippi_streams(slippi_data_streams: Vec<Pin<Box<SlippiDataStream>>>, st
     Ok(map)
 }
 
+/// Save each game passing through a merged stream to a .slp file, in a
+/// subdirectory of `archive_directory` named after its stream ID.
+fn archive_slippi_streams(
+    stream: impl Stream<Item = (u32, Vec<u8>)>,
+    archive_directory: PathBuf,
+    replay_name_template: String
+) -> impl Stream<Item = (u32, Vec<u8>)> {
+    let mut writers: HashMap<u32, SlpFileWriter> = HashMap::new();
+
+    stream.inspect(move |(stream_id, data)| {
+        let writer = writers.entry(*stream_id).or_insert_with(|| {
+            SlpFileWriter::with_directory(archive_directory.join(stream_id.to_string()), Some(*stream_id), &replay_name_template)
+        });
+
+        // Archiving is best effort, and should not interrupt the broadcast
+        if let Err(e) = writer.write_all(data) {
+            tracing::warn!("Error archiving game data for stream {}: {}", stream_id, e);
+        }
+    })
+}
+
+/// Publish the state of the game on each stream in a merged stream to an
+/// overlay feed.
+fn track_slippi_streams(stream: impl Stream<Item = (u32, Vec<u8>)>, overlay: OverlayFeed) -> impl Stream<Item = (u32, Vec<u8>)> {
+    let mut trackers: HashMap<u32, GameStateTracker> = HashMap::new();
+
+    stream.inspect(move |(stream_id, data)| {
+        trackers.entry(*stream_id)
+            .or_insert_with(|| overlay.track(Some(*stream_id)))
+            .process(data);
+    })
+}
+
+/// How much data may be held back for one stream by a broadcast delay. If a
+/// stream exceeds this, the data held back for it is discarded rather than
+/// sent early, since the delay must never be shortened.
+const MAX_DELAYED_BYTES_PER_STREAM: usize = 64 * 1024 * 1024;
+
+/// Holds back broadcast data to delay the public stream, for example so that
+/// players at a tournament cannot watch their opponents.
+#[derive(Debug, Clone)]
+pub struct BroadcastDelay {
+    pub delay: Duration,
+    /// What to do with data still being held back when the Slippi streams
+    /// end: send it once its delay is up, or discard it.
+    pub flush_on_end: bool,
+}
+
+enum DelayEvent {
+    Received(Option<(u32, Vec<u8>)>),
+    Release,
+}
+
+/// Delay each stream in a merged stream by `delay`.
+fn delay_slippi_streams(
+    stream: impl Stream<Item = (u32, Vec<u8>)> + Send + 'static,
+    delay: BroadcastDelay
+) -> impl Stream<Item = (u32, Vec<u8>)> {
+    stream! {
+        let mut input = Box::pin(stream);
+        let mut input_ended = false;
+        // The data of all streams, in the order it was received, along with
+        // when it may be sent.
+        let mut queue: VecDeque<(Instant, u32, Vec<u8>)> = VecDeque::new();
+        let mut delayed_bytes: HashMap<u32, usize> = HashMap::new();
+
+        loop {
+            let next_release = queue.front().map(|(release_at, _, _)| *release_at);
+
+            let event = tokio::select! {
+                received = input.next(), if !input_ended => DelayEvent::Received(received),
+                _ = sleep_until(next_release.unwrap_or_else(Instant::now)), if next_release.is_some() => DelayEvent::Release,
+                else => break
+            };
+
+            match event {
+                DelayEvent::Received(Some((stream_id, data))) => {
+                    let stream_bytes = delayed_bytes.entry(stream_id).or_default();
+                    *stream_bytes += data.len();
+                    queue.push_back((Instant::now() + delay.delay, stream_id, data));
+
+                    if *stream_bytes > MAX_DELAYED_BYTES_PER_STREAM {
+                        tracing::error!("Too much data delayed for stream {}, discarding it", stream_id);
+                        queue.retain(|(_, id, _)| *id != stream_id);
+                        *stream_bytes = 0;
+                    }
+                }
+                DelayEvent::Received(None) => {
+                    input_ended = true;
+
+                    if queue.is_empty() {
+                        break;
+                    } else if delay.flush_on_end {
+                        tracing::info!("Sending the remaining delayed data over the next {:?}...", delay.delay);
+                    } else {
+                        tracing::info!("Discarding the remaining delayed data.");
+                        break;
+                    }
+                }
+                DelayEvent::Release => {
+                    let (_, stream_id, data) = queue.pop_front().unwrap();
+                    *delayed_bytes.get_mut(&stream_id).unwrap() -= data.len();
+                    yield (stream_id, data);
+                }
+            }
+        }
+    }
+}
+
 /* Packet spec
  * +------------------------------+
  * | stream ID (32 bits, 4 bytes) |
@@ -51,7 +170,8 @@ fn merge_slippi_streams(slippi_data_streams: Vec<Pin<Box<SlippiDataStream>>>, st
  */
 
  /// Send data from a stream of merged `SlippiDataStream`s to a
- /// SpectatorMode connection.
+ /// SpectatorMode connection. Data is keyed by the stream IDs first assigned,
+ /// which the client translates to the current ones as it is sent.
  /// This function has the future completion properties of [`futures::stream::StreamExt::forward`],
  /// which means that successful exhaustion of the stream will flush and close
  /// the client sink, but a stream error will not do so. However, since a `SlippiDataStream`
@@ -61,20 +181,50 @@ fn merge_slippi_streams(slippi_data_streams: Vec<Pin<Box<SlippiDataStream>>>, st
  fn forward_slippi_data(stream: impl Stream<Item = (u32, Vec<u8>)>, sm_client: SpectatorModeClient) -> impl Future<Output = Result<(), SpectatorModeClientError>> {
     stream.filter_map(async |(k, v)| {
         if v.len() > 0 {
-            Some(Ok(create_packet(k, v)))
+            Some(Ok((k, v)))
         } else {
             None
         }
   }).forward(sm_client)
 }
 
+/// Additional processing of the data forwarded to SpectatorMode.
+#[derive(Debug, Clone, Default)]
+pub struct ForwardOptions {
+    /// Also save the games forwarded to .slp files in this directory, in a
+    /// subdirectory per stream ID. Games are saved as they are played,
+    /// regardless of `delay`.
+    pub archive_directory: Option<PathBuf>,
+    /// How to name archived games, as described in
+    /// [`crate::spectate::replay_name`]. Defaults to `Game_{timestamp}`.
+    pub archive_name_template: Option<String>,
+    /// Delay the data forwarded.
+    pub delay: Option<BroadcastDelay>,
+    /// Publish the state of the games forwarded to this feed. This follows
+    /// `delay`, to match what viewers see.
+    pub overlay: Option<OverlayFeed>,
+}
+
 /// Forward one or more streams to SpectatorMode as one bridge connection.
 pub fn forward_streams(
     slippi_data_streams: Vec<Pin<Box<SlippiDataStream>>>,
     stream_ids: Vec<u32>,
-    sm_client: SpectatorModeClient
+    sm_client: SpectatorModeClient,
+    options: ForwardOptions
 ) -> impl Future<Output = Result<(), SpectatorModeClientError>> {
-    let merged_stream = merge_slippi_streams(slippi_data_streams, stream_ids).unwrap();
+    let mut merged_stream = merge_slippi_streams(slippi_data_streams, stream_ids).unwrap().boxed();
+
+    if let Some(archive_directory) = options.archive_directory {
+        let replay_name_template = options.archive_name_template.unwrap_or(DEFAULT_REPLAY_NAME_TEMPLATE.to_string());
+        merged_stream = archive_slippi_streams(merged_stream, archive_directory, replay_name_template).boxed();
+    }
+    if let Some(delay) = options.delay {
+        merged_stream = delay_slippi_streams(merged_stream, delay).boxed();
+    }
+    if let Some(overlay) = options.overlay {
+        merged_stream = track_slippi_streams(merged_stream, overlay).boxed();
+    }
+
     forward_slippi_data(merged_stream, sm_client)
 }
 
@@ -88,7 +238,7 @@ fn create_header(data: &[u32; 2]) -> [u8; 8] {
     res
 }
 
-fn create_packet(stream_id: u32, mut data: Vec<u8>) -> Bytes {
+pub(crate) fn create_packet(stream_id: u32, mut data: Vec<u8>) -> Bytes {
     // TODO: Could this be more efficient by using BytesMut?
     //   Think this would have to depend on the bytes package, and the packet sizes
     //   would have to be known beforehand.
@@ -98,6 +248,30 @@ fn create_packet(stream_id: u32, mut data: Vec<u8>) -> Bytes {
     Bytes::from(packet)
 }
 
+/// Split a message received from a bridge into its packets, as pairs of stream
+/// ID and data. A message may contain packets for multiple streams.
+pub(crate) fn parse_packets(mut message: &[u8]) -> Result<Vec<(u32, &[u8])>, &'static str> {
+    let mut packets = Vec::new();
+
+    while !message.is_empty() {
+        if message.len() < 8 {
+            return Err("incomplete packet header");
+        }
+
+        let stream_id = u32::from_le_bytes(message[0..4].try_into().unwrap());
+        let size = u32::from_le_bytes(message[4..8].try_into().unwrap()) as usize;
+
+        if message.len() < 8 + size {
+            return Err("packet is shorter than its declared size");
+        }
+
+        packets.push((stream_id, &message[8..(8 + size)]));
+        message = &message[(8 + size)..];
+    }
+
+    Ok(packets)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -121,4 +295,38 @@ mod tests {
         assert_eq!(size_vec, vec![10, 0, 0, 0]);
         assert_eq!(data_vec, vec![255, 60, 75, 0, 1, 127, 205, 15, 99, 191]);
     }
+
+    #[tokio::test]
+    async fn delay_slippi_streams_holds_data_back() {
+        let delay = Duration::from_millis(100);
+        let input = futures::stream::iter([(1, vec![0x35]), (2, vec![0x36]), (1, vec![0x39])]);
+
+        let start = Instant::now();
+        let output: Vec<(u32, Vec<u8>)> = delay_slippi_streams(input, BroadcastDelay { delay, flush_on_end: true }).collect().await;
+        assert!(start.elapsed() >= delay);
+        assert_eq!(output, vec![(1, vec![0x35]), (2, vec![0x36]), (1, vec![0x39])]);
+
+        let input = futures::stream::iter([(1, vec![0x35])]);
+        let output: Vec<(u32, Vec<u8>)> = delay_slippi_streams(input, BroadcastDelay { delay, flush_on_end: false }).collect().await;
+        assert_eq!(output, vec![]);
+    }
+
+    #[tokio::test]
+    async fn delay_slippi_streams_discards_overflowing_stream() {
+        let delay = Duration::from_millis(10);
+        let large = vec![0; MAX_DELAYED_BYTES_PER_STREAM / 2 + 1];
+        let input = futures::stream::iter([(1, large.clone()), (2, vec![0x35]), (1, large), (1, vec![0x36])]);
+
+        let output: Vec<(u32, Vec<u8>)> = delay_slippi_streams(input, BroadcastDelay { delay, flush_on_end: true }).collect().await;
+        assert_eq!(output, vec![(2, vec![0x35]), (1, vec![0x36])]);
+    }
+
+    #[test]
+    fn parse_packets_reads_created_packets() {
+        let mut message = create_packet(1, vec![0x35, 1]).to_vec();
+        message.extend_from_slice(&create_packet(2, vec![0x39]));
+
+        assert_eq!(parse_packets(&message), Ok(vec![(1, &[0x35, 1][..]), (2, &[0x39][..])]));
+        assert!(parse_packets(&message[..(message.len() - 1)]).is_err());
+    }
 }
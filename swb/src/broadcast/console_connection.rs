@@ -1,17 +1,20 @@
 use std::{
     net::SocketAddr, pin::Pin, time::Duration
 };
-use serde::{Deserialize, Serialize};
-use futures::channel::mpsc::Receiver;
+use serde::{Deserialize, Serialize, Serializer};
+use futures::{channel::mpsc::Receiver, StreamExt};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
-    time::timeout
+    time::{sleep, timeout}
 };
 use async_stream::stream;
 use thiserror::Error;
 
-use crate::common::SlippiDataStream;
+use crate::{
+    broadcast::{SlippiConnectionInfo, SlippiLifecycleEvent, SlippiLifecycleSender},
+    common::{GameTracker, SlippiDataStream}
+};
 
 #[derive(Debug, Deserialize, Serialize)]
 struct CommunicationMessage {
@@ -22,16 +25,55 @@ struct CommunicationMessage {
 #[derive(Debug, Deserialize, Serialize, Default)]
 #[allow(non_snake_case)]
 struct CommunicationMessagePayload {
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_uint8_array")]
     cursor: Option<Vec<u8>>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_uint8_array")]
     clientToken: Option<Vec<u8>>,
-    pos: Vec<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    isRealtime: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pos: Option<Vec<u8>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     nextPos: Option<Vec<u8>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     data: Option<Vec<u8>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     nick: Option<String>,
-    force_pos: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    forcePos: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     nintendontVersion: Option<String>
 }
 
+/// Bytes which serialize as a strongly-typed UBJSON uint8 array, as the
+/// console expects, rather than as an array of integers.
+struct Uint8Array<'a>(&'a [u8]);
+
+impl Serialize for Uint8Array<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+fn serialize_uint8_array<S: Serializer>(bytes: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+    bytes.as_deref().map(Uint8Array).serialize(serializer)
+}
+
+/// Options for connecting to a console.
+#[derive(Debug, Clone, Default)]
+pub struct ConsoleOptions {
+    /// Position in the console's data buffer to start reading from. If
+    /// unspecified, the console decides, which generally means the start of
+    /// the current game.
+    pub cursor: Option<u64>,
+    /// Token identifying this client to the console, as returned by a
+    /// previous handshake.
+    pub client_token: Option<u32>,
+    /// Request data from the console with lower latency, at the cost of
+    /// possibly dropping data if the connection cannot keep up.
+    pub realtime: bool,
+}
+
 #[derive(Error, Debug)]
 pub enum ConsoleCommunicationError {
     #[error("Connection error: {0}")]
@@ -42,28 +84,164 @@ pub enum ConsoleCommunicationError {
 
     #[error("Decode error: {0}")]
     DecodeError(#[from] ubjson_rs::UbjsonError),
+
+    #[error("Encode error: {0}")]
+    EncodeError(ubjson_rs::UbjsonError),
+
+    #[error("Timed out connecting to console")]
+    ConnectTimeout,
 }
 
-async fn establish_console_connection(addr: SocketAddr) -> Result<TcpStream, ConsoleCommunicationError> {
+// https://github.com/project-slippi/slippi-js/blob/master/src/console/communication.ts
+const MESSAGE_TYPE_HANDSHAKE: u8 = 1;
+const MESSAGE_TYPE_REPLAY: u8 = 2;
+
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// How long to wait for the console to accept a connection or reply to a
+/// message.
+const CONSOLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// State kept across console connections to allow resuming the data stream
+/// from where it left off after a reconnect.
+#[derive(Debug, Default)]
+struct ConnectionDetails {
+    /// Position of the next data expected from the console.
+    game_data_cursor: [u8; 8],
+    /// Token the console assigned to this client in its handshake reply.
+    client_token: [u8; 4],
+    is_realtime: bool,
+    console_nick: Option<String>,
+    nintendont_version: Option<String>,
+}
+
+impl ConnectionDetails {
+    fn new(options: &ConsoleOptions) -> Self {
+        Self {
+            game_data_cursor: options.cursor.unwrap_or(0).to_be_bytes(),
+            client_token: options.client_token.unwrap_or(0).to_be_bytes(),
+            is_realtime: options.realtime,
+            console_nick: None,
+            nintendont_version: None,
+        }
+    }
+
+    fn connection_info(&self) -> SlippiConnectionInfo {
+        SlippiConnectionInfo {
+            nickname: self.console_nick.clone(),
+            version: self.nintendont_version.clone(),
+            client_token: Some(u32::from_be_bytes(self.client_token)),
+        }
+    }
+
+    /// Build the handshake to send to the console upon connection, prefixed
+    /// by its size.
+    fn handshake_message(&self) -> Result<Vec<u8>, ConsoleCommunicationError> {
+        let message = CommunicationMessage {
+            r#type: MESSAGE_TYPE_HANDSHAKE,
+            payload: Some(CommunicationMessagePayload {
+                cursor: Some(self.game_data_cursor.to_vec()),
+                clientToken: Some(self.client_token.to_vec()),
+                isRealtime: Some(self.is_realtime),
+                ..Default::default()
+            })
+        };
+
+        let encoded = ubjson_rs::to_vec(&message).map_err(ConsoleCommunicationError::EncodeError)?;
+        let mut handshake = (encoded.len() as u32).to_be_bytes().to_vec();
+        handshake.extend(encoded);
+        Ok(handshake)
+    }
+
+    /// Update connection details from a console message, and return the game
+    /// data it contains, if any.
+    fn process_message(&mut self, message: CommunicationMessage) -> Option<Vec<u8>> {
+        let payload = message.payload?;
+
+        match message.r#type {
+            MESSAGE_TYPE_HANDSHAKE => {
+                if let Some(client_token) = payload.clientToken.as_deref().and_then(|t| t.try_into().ok()) {
+                    self.client_token = client_token;
+                }
+                if let Some(pos) = payload.pos.as_deref().and_then(|p| p.try_into().ok()) {
+                    self.game_data_cursor = pos;
+                }
+                if payload.nick.is_some() {
+                    self.console_nick = payload.nick;
+                }
+                if payload.nintendontVersion.is_some() {
+                    self.nintendont_version = payload.nintendontVersion;
+                }
+                None
+            }
+            MESSAGE_TYPE_REPLAY => {
+                if payload.pos.as_deref() != Some(&self.game_data_cursor[..]) && !payload.forcePos.unwrap_or(false) {
+                    // Data was either already received, or some was skipped
+                    // without the console telling us; either way, don't
+                    // forward it.
+                    tracing::warn!(
+                        "Unexpected console data position, expected {:?} but got {:?}; skipping",
+                        self.game_data_cursor,
+                        payload.pos
+                    );
+                    return None;
+                }
+
+                if let Some(next_pos) = payload.nextPos.as_deref().and_then(|p| p.try_into().ok()) {
+                    self.game_data_cursor = next_pos;
+                }
+                payload.data
+            }
+            _ => None
+        }
+    }
+}
+
+async fn establish_console_connection(addr: SocketAddr, connection_details: &ConnectionDetails) -> Result<TcpStream, ConsoleCommunicationError> {
+    let handshake = connection_details.handshake_message()?;
+
+    let tcp_stream = timeout(CONSOLE_TIMEOUT, TcpStream::connect(addr)).await
+        .map_err(|_| ConsoleCommunicationError::ConnectTimeout)?;
+
     let result: Result<TcpStream, std::io::Error> = async {
-        let mut tcp_stream = TcpStream::connect(addr).await?;
-        let dummy_handshake_out: Vec<u8> = vec![
-            0x00, 0x00, 0x00, 0x4f, 0x7b, 0x69, 0x04, 0x74, 0x79, 0x70, 0x65,
-            0x69, 0x01, 0x69, 0x07, 0x70, 0x61, 0x79, 0x6c, 0x6f, 0x61, 0x64,
-            0x7b, 0x69, 0x06, 0x63, 0x75, 0x72, 0x73, 0x6f, 0x72, 0x5b, 0x24,
-            0x55, 0x23, 0x69, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x69, 0x0b, 0x63, 0x6c, 0x69, 0x65, 0x6e, 0x74, 0x54, 0x6f,
-            0x6b, 0x65, 0x6e, 0x5b, 0x24, 0x55, 0x23, 0x69, 0x04, 0x00, 0x00,
-            0x00, 0x00, 0x69, 0x0a, 0x69, 0x73, 0x52, 0x65, 0x61, 0x6c, 0x74,
-            0x69, 0x6d, 0x65, 0x46, 0x7d, 0x7d
-        ];
-        tcp_stream.write_all(&dummy_handshake_out).await?;
+        let mut tcp_stream = tcp_stream?;
+        tcp_stream.write_all(&handshake).await?;
         Ok(tcp_stream)
     }.await;
 
     result.map_err(|io_error| ConsoleCommunicationError::SocketConnectionError(io_error))
 }
 
+/// Re-establish a console connection, retrying with backoff until it succeeds.
+/// Returns `None` if an interrupt is received before reconnecting.
+async fn reconnect(
+    addr: SocketAddr,
+    connection_details: &ConnectionDetails,
+    interrupt_receiver: &mut Receiver<bool>
+) -> Option<TcpStream> {
+    let mut delay = Duration::from_secs(1);
+
+    loop {
+        tracing::info!("Reconnecting to console at {} in {:?}...", addr, delay);
+
+        tokio::select! {
+            _ = interrupt_receiver.next() => return None,
+            _ = sleep(delay) => ()
+        }
+
+        match establish_console_connection(addr, connection_details).await {
+            Ok(tcp_stream) => {
+                tracing::info!("Reconnected to console.");
+                return Some(tcp_stream);
+            }
+            Err(e) => {
+                tracing::warn!("Failed to reconnect to console: {}", e);
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+        }
+    }
+}
+
 async fn read_next_message(tcp_stream: &mut TcpStream) -> Result<CommunicationMessage, ConsoleCommunicationError> {
     // TODO: Map Err value `Os { code: 61, kind: ConnectionRefused, message: "Connection refused" }` to ConsoleConnectionError
     let msg_size = tcp_stream.read_u32().await?;
@@ -74,10 +252,31 @@ async fn read_next_message(tcp_stream: &mut TcpStream) -> Result<CommunicationMe
     Ok(result)
 }
 
-pub async fn data_stream(addr:  SocketAddr, mut interrupt_receiver: Receiver<bool>) -> Pin<Box<SlippiDataStream>> {
-    let mut tcp_stream = establish_console_connection(addr).await.unwrap();
+pub async fn data_stream(
+    addr: SocketAddr,
+    options: ConsoleOptions,
+    mut interrupt_receiver: Receiver<bool>,
+    lifecycle_sender: SlippiLifecycleSender
+) -> Result<(Pin<Box<SlippiDataStream>>, SlippiConnectionInfo), ConsoleCommunicationError> {
+    let mut connection_details = ConnectionDetails::new(&options);
+    let mut game_tracker = GameTracker::default();
+    let mut tcp_stream = establish_console_connection(addr, &connection_details).await?;
+
+    // The console replies to the handshake with its connection details before
+    // sending any game data.
+    let handshake_reply = timeout(CONSOLE_TIMEOUT, read_next_message(&mut tcp_stream)).await
+        .map_err(|_| ConsoleCommunicationError::ConnectTimeout)??;
+    let initial_data = connection_details.process_message(handshake_reply);
+    let connection_info = connection_details.connection_info();
+
+    let stream = Box::pin(stream! {
+        if let Some(data) = initial_data {
+            for (_, event) in game_tracker.process(&data) {
+                let _ = lifecycle_sender.unbounded_send(event);
+            }
+            yield data;
+        }
 
-    Box::pin(stream! {
         loop {
             match interrupt_receiver.try_next() {
                 Ok(Some(_)) => {
@@ -86,34 +285,124 @@ pub async fn data_stream(addr:  SocketAddr, mut interrupt_receiver: Receiver<boo
                 }
                 Ok(None) => {
                     // interrupt channel is closed; something is wrong
-                    // TODO: Try to reconnect if it makes sense
                     tracing::error!("Interrupt channel closed unexpectedly");
                     break
                 }
                 _ => ()
             }
 
-            match timeout(Duration::from_secs(5), read_next_message(&mut tcp_stream)).await {
-                Ok(read_result) => {
-                    match read_result {
-                        Ok(message) => {
-                            match message.payload {
-                                None => continue,
-                                Some(payload) => yield payload.data.unwrap_or(Vec::new())
-                            }
-                        }
-                        Err(e) => {
-                            tracing::error!("Error reading console message: {:?}", e);
-                            break
+            match timeout(CONSOLE_TIMEOUT, read_next_message(&mut tcp_stream)).await {
+                Ok(Ok(message)) => {
+                    if let Some(data) = connection_details.process_message(message) {
+                        for (_, event) in game_tracker.process(&data) {
+                            let _ = lifecycle_sender.unbounded_send(event);
                         }
+                        yield data;
                     }
+                    continue
+                }
+                Ok(Err(e)) => {
+                    tracing::error!("Error reading console message: {}", e);
                 }
                 Err(_) => {
-                    // TODO: Reconnect instead
                     tracing::error!("Timeout receiving next message");
-                    break
                 }
             }
+
+            let _ = lifecycle_sender.unbounded_send(SlippiLifecycleEvent::Disconnected);
+
+            match reconnect(addr, &connection_details, &mut interrupt_receiver).await {
+                Some(new_tcp_stream) => {
+                    tcp_stream = new_tcp_stream;
+                    let _ = lifecycle_sender.unbounded_send(SlippiLifecycleEvent::Reconnected);
+                }
+                None => break
+            }
         }
-    })
+    });
+
+    Ok((stream, connection_info))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replay_message(pos: u8, next_pos: u8, force_pos: Option<bool>) -> CommunicationMessage {
+        CommunicationMessage {
+            r#type: MESSAGE_TYPE_REPLAY,
+            payload: Some(CommunicationMessagePayload {
+                pos: Some(vec![0, 0, 0, 0, 0, 0, 0, pos]),
+                nextPos: Some(vec![0, 0, 0, 0, 0, 0, 0, next_pos]),
+                data: Some(vec![pos; (next_pos - pos) as usize]),
+                forcePos: force_pos,
+                ..Default::default()
+            })
+        }
+    }
+
+    #[test]
+    fn process_message_skips_repeated_data() {
+        let mut connection_details = ConnectionDetails::default();
+
+        assert_eq!(connection_details.process_message(replay_message(0, 2, None)), Some(vec![0, 0]));
+        assert_eq!(connection_details.process_message(replay_message(0, 2, None)), None);
+        assert_eq!(connection_details.process_message(replay_message(2, 3, None)), Some(vec![2]));
+        assert_eq!(connection_details.process_message(replay_message(5, 6, Some(true))), Some(vec![5]));
+        assert_eq!(connection_details.game_data_cursor, [0, 0, 0, 0, 0, 0, 0, 6]);
+    }
+
+    /// Handshake with a zeroed cursor and client token, and isRealtime false,
+    /// as sent before connection options were supported.
+    const LEGACY_HANDSHAKE: [u8; 83] = [
+        0x00, 0x00, 0x00, 0x4f, 0x7b, 0x69, 0x04, 0x74, 0x79, 0x70, 0x65,
+        0x69, 0x01, 0x69, 0x07, 0x70, 0x61, 0x79, 0x6c, 0x6f, 0x61, 0x64,
+        0x7b, 0x69, 0x06, 0x63, 0x75, 0x72, 0x73, 0x6f, 0x72, 0x5b, 0x24,
+        0x55, 0x23, 0x69, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x69, 0x0b, 0x63, 0x6c, 0x69, 0x65, 0x6e, 0x74, 0x54, 0x6f,
+        0x6b, 0x65, 0x6e, 0x5b, 0x24, 0x55, 0x23, 0x69, 0x04, 0x00, 0x00,
+        0x00, 0x00, 0x69, 0x0a, 0x69, 0x73, 0x52, 0x65, 0x61, 0x6c, 0x74,
+        0x69, 0x6d, 0x65, 0x46, 0x7d, 0x7d
+    ];
+
+    #[test]
+    fn handshake_message_contains_connection_details() {
+        let default_handshake = ConnectionDetails::new(&ConsoleOptions::default()).handshake_message().unwrap();
+        assert_eq!(default_handshake, LEGACY_HANDSHAKE);
+
+        let connection_details = ConnectionDetails::new(&ConsoleOptions {
+            cursor: Some(73021),
+            client_token: Some(265291293),
+            realtime: true,
+        });
+
+        let mut expected = LEGACY_HANDSHAKE;
+        expected[37..][..8].copy_from_slice(&[0, 0, 0, 0, 0, 1, 29, 61]);
+        expected[64..][..4].copy_from_slice(&[15, 208, 6, 29]);
+        expected[80] = b'T';
+        assert_eq!(connection_details.handshake_message().unwrap(), expected);
+    }
+
+    #[test]
+    fn process_message_reads_handshake_reply() {
+        let mut connection_details = ConnectionDetails::default();
+        let message = CommunicationMessage {
+            r#type: MESSAGE_TYPE_HANDSHAKE,
+            payload: Some(CommunicationMessagePayload {
+                nick: Some("louloute".to_string()),
+                nintendontVersion: Some("1.13.0".to_string()),
+                clientToken: Some(vec![15, 208, 6, 29]),
+                pos: Some(vec![0, 0, 0, 0, 0, 1, 29, 61]),
+                ..Default::default()
+            })
+        };
+
+        assert_eq!(connection_details.process_message(message), None);
+
+        let connection_info = connection_details.connection_info();
+        assert_eq!(connection_info.nickname.as_deref(), Some("louloute"));
+        assert_eq!(connection_info.version.as_deref(), Some("1.13.0"));
+        assert_eq!(connection_info.client_token, Some(265291293));
+        assert_eq!(connection_details.game_data_cursor, [0, 0, 0, 0, 0, 1, 29, 61]);
+    }
 }
@@ -1,208 +1,299 @@
 use std::{
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket}, pin::Pin, str, thread, time::Duration
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket}, pin::Pin, str, time::Duration
 };
 
 use base64::{Engine, prelude::BASE64_STANDARD};
-use futures::{
-    channel::mpsc::Receiver,
-    stream,
-    task::{Context, Poll},
-};
+use futures::{channel::mpsc::Receiver, StreamExt};
 use rusty_enet::{self as enet};
-use serde::de::Error;
-use serde_json::{Result as SerdeResult, Value};
-use tokio::time::interval;
+use serde_json::Value;
+use thiserror::Error;
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::{interval, timeout, MissedTickBehavior}
+};
+use tokio_stream::wrappers::ReceiverStream;
 
+use crate::{
+    broadcast::{SlippiConnectionInfo, SlippiLifecycleEvent, SlippiLifecycleSender},
+    common::SlippiDataStream
+};
 
-use crate::common::SlippiDataStream;
+/// Options for connecting to Slippi Dolphin.
+#[derive(Debug, Clone, Default)]
+pub struct DolphinOptions {
+    /// Keep trying to reconnect if Dolphin disconnects, for example because
+    /// it was closed or crashed, rather than ending the stream.
+    pub reconnect: bool,
+}
 
-struct DolphinHost {
-    host: enet::Host<UdpSocket>,
-    interrupt_receiver: Receiver<bool>
+#[derive(Error, Debug)]
+pub enum DolphinConnectionError {
+    #[error("Socket error: {0}")]
+    SocketError(#[from] std::io::Error),
+
+    #[error("Host error: {0}")]
+    HostError(&'static str),
+
+    #[error("Invalid packet: {0}")]
+    PacketError(&'static str),
+
+    #[error("Timed out waiting for Dolphin to connect")]
+    ConnectTimeout,
+
+    #[error("Connection closed before Dolphin connected")]
+    ConnectionClosed,
 }
 
-fn full_service(
-    host: DolphinHost,
-    peer_id: enet::PeerID
-) -> Result<(DolphinHost, Vec<ConnectionEvent>), &'static str> {
-    let mut events: Vec<ConnectionEvent> = Vec::new();
+#[derive(Debug)]
+pub enum ConnectionEvent {
+    Connect { nick: Option<String>, version: Option<String> },
+    Disconnect,
+    Message { payload: Vec<u8> },
+    StartGame,
+    EndGame,
+}
 
-    let mut host_cycle = host;
+const MAX_PEERS: usize = 32;
 
-    loop {
-        let (new_host, result) = service(host_cycle, peer_id);
-        host_cycle = new_host;
-        match result? {
-            None => break,
-            Some(event) => events.push(event),
-        }
-    }
+/// How long to wait for Dolphin to reply to the initial connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 
-    Ok((host_cycle, events))
+struct DolphinHost {
+    host: enet::Host<UdpSocket>,
+    addr: SocketAddr,
+    peer_id: enet::PeerID,
+    /// Position of the next game event expected from Dolphin.
+    cursor: u64,
+    reconnect: bool,
+    interrupted: bool,
 }
 
-// TODO: Probably makes more sense to return Result as outer layer
-fn service(
-    mut dolphin_host: DolphinHost,
-    peer_id: enet::PeerID
-) -> (DolphinHost, Result<Option<ConnectionEvent>, &'static str>) {
+impl DolphinHost {
+    fn new(addr: SocketAddr, options: &DolphinOptions) -> Result<DolphinHost, DolphinConnectionError> {
+        let socket = UdpSocket::bind(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)))?;
+
+        let mut host =
+            enet::Host::<UdpSocket>::new(
+                socket,
+                enet::HostSettings {
+                    peer_limit: MAX_PEERS,
+                    channel_limit: 3,
+                    ..Default::default()
+                },
+            )
+            .map_err(|_| DolphinConnectionError::HostError("failed to create host"))?;
 
-    if let Ok(Some(_)) = dolphin_host.interrupt_receiver.try_next() {
-        let new_host = initiate_disconnect(dolphin_host, peer_id);
-        return (new_host, Ok(None));
+        // Initiate connection
+        let peer = host.connect(addr, 3, 1337).map_err(|_| DolphinConnectionError::HostError("failed to connect"))?;
+        peer.set_ping_interval(100);
+        let peer_id = peer.id();
+
+        Ok(DolphinHost {
+            host,
+            addr,
+            peer_id,
+            cursor: 0,
+            reconnect: options.reconnect,
+            interrupted: false,
+        })
     }
 
-    let result =
-    match dolphin_host.host.service() {
-        Err(_) => Err("host service error"),
+    fn reconnect(&mut self) -> Result<(), DolphinConnectionError> {
+        let peer = self.host.connect(self.addr, 3, 1337).map_err(|_| DolphinConnectionError::HostError("failed to connect"))?;
+        peer.set_ping_interval(100);
+        self.peer_id = peer.id();
+        Ok(())
+    }
 
-        Ok(None) => Ok(None),
+    fn disconnect(&mut self) {
+        tracing::info!("Disconnecting from Slippi...");
+        self.interrupted = true;
+        let peer = self.host.peer_mut(self.peer_id);
+        peer.disconnect(1337);
+    }
 
-        Ok(Some(event)) => {
+    /// Process all events currently available from the host.
+    fn full_service(&mut self) -> Result<Vec<ConnectionEvent>, DolphinConnectionError> {
+        let mut events: Vec<ConnectionEvent> = Vec::new();
+
+        while let Some(event) = self.host.service().map_err(|_| DolphinConnectionError::HostError("host service error"))? {
             match event {
                 enet::Event::Connect { peer, .. } => {
                     let packet = enet::Packet::reliable(
-                        r#"{"type":"connect_request","cursor":0}"#.as_bytes(),
+                        format!(r#"{{"type":"connect_request","cursor":{}}}"#, self.cursor).as_bytes(),
                     );
                     _ = peer.send(0, &packet);
-                    Ok(None)
                 }
-                enet::Event::Disconnect { .. } => Ok(Some(ConnectionEvent::Disconnect)),
+                enet::Event::Disconnect { .. } => events.push(ConnectionEvent::Disconnect),
                 enet::Event::Receive { packet, .. } => {
-                    if let Ok(message) = str::from_utf8(packet.data()) {
-                        // println!("Received packet: {:?}", message);
-
-                        // https://stackoverflow.com/questions/65575385/deserialization-of-json-with-serde-by-a-numerical-value-as-type-identifier/65576570#65576570
-
-                        // let connect_reply: ConnectReply = serde_json::from_str(message).expect("Error deserializing JSON");
-                        // Parse the string of data into serde_json::Value.
-                        let v: Value = serde_json::from_str(message).unwrap();
-                        let packet_type_result: SerdeResult<String> = match &v["type"] {
-                            Value::String(s) => Ok(s.to_string()),
-                            _ => Err(Error::custom("something")),
-                        };
-                        let packet_type = packet_type_result.unwrap();
-
-                        match packet_type.as_str() {
-                            "connect_reply" => Ok(Some(ConnectionEvent::Connect)),
-                            "game_event" => {
-                                if let Value::String(encoded_payload) = &v["payload"] {
-                                    let payload = BASE64_STANDARD.decode(encoded_payload).unwrap();
-                                    Ok(Some(ConnectionEvent::Message { payload }))
-                                } else {
-                                    Err("payload access error")
-                                }
-                            }
-                            "start_game" => Ok(Some(ConnectionEvent::StartGame)),
-                            "end_game" => Ok(Some(ConnectionEvent::EndGame)),
-                            _ => Err("unexpected packet type"),
-                        }
-                    } else {
-                        Err("failed to decode packet")
+                    if let Some(connection_event) = parse_packet(packet.data(), &mut self.cursor)? {
+                        events.push(connection_event);
                     }
                 }
             }
         }
-    };
 
-    (dolphin_host, result)
+        Ok(events)
+    }
 }
 
-fn initiate_disconnect(mut dolphin_host: DolphinHost, peer_id: enet::PeerID) -> DolphinHost {
-    tracing::info!("Disconnecting from Slippi...");
-    let peer = dolphin_host.host.peer_mut(peer_id);
-    peer.disconnect(1337);
-    dolphin_host
-}
+/// Parse a packet received from Dolphin, updating the game data cursor as
+/// needed. Returns `None` for game data which has already been received.
+fn parse_packet(data: &[u8], cursor: &mut u64) -> Result<Option<ConnectionEvent>, DolphinConnectionError> {
+    let message = str::from_utf8(data).map_err(|_| DolphinConnectionError::PacketError("failed to decode packet"))?;
+    let v: Value = serde_json::from_str(message).map_err(|_| DolphinConnectionError::PacketError("failed to parse packet"))?;
 
-#[derive(Debug)]
-pub enum ConnectionEvent {
-    Connect,
-    Disconnect,
-    Message { payload: Vec<u8> },
-    StartGame,
-    EndGame,
-}
-
-const MAX_PEERS: usize = 32;
+    match v["type"].as_str() {
+        Some("connect_reply") => {
+            if let Some(reply_cursor) = v["cursor"].as_u64() {
+                *cursor = reply_cursor;
+            }
+            Ok(Some(ConnectionEvent::Connect {
+                nick: v["nick"].as_str().map(str::to_string),
+                version: v["version"].as_str().map(str::to_string),
+            }))
+        }
+        Some("game_event") => {
+            let event_cursor = v["cursor"].as_u64().unwrap_or(*cursor);
+            if event_cursor < *cursor {
+                // Already received before reconnecting
+                return Ok(None);
+            } else if event_cursor > *cursor {
+                tracing::warn!("Unexpected Dolphin game data cursor, expected {} but got {}", cursor, event_cursor);
+            }
+            if let Some(next_cursor) = v["next_cursor"].as_u64() {
+                *cursor = next_cursor;
+            }
 
-fn wait_for_connected(
-    dolphin_host: DolphinHost,
-    peer_id: enet::PeerID
-) -> DolphinHost {
-    let mut host_cycle = dolphin_host;
-    loop {
-        let (new_host, result) = service(host_cycle, peer_id);
-        host_cycle = new_host;
-        match result {
-            Ok(Some(ConnectionEvent::Connect)) => return host_cycle,
-            _ => ()
+            let encoded_payload = v["payload"].as_str().ok_or(DolphinConnectionError::PacketError("payload access error"))?;
+            let payload = BASE64_STANDARD.decode(encoded_payload).map_err(|_| DolphinConnectionError::PacketError("payload decode error"))?;
+            Ok(Some(ConnectionEvent::Message { payload }))
         }
-        thread::sleep(Duration::from_millis(10));
+        Some("start_game") => Ok(Some(ConnectionEvent::StartGame)),
+        Some("end_game") => Ok(Some(ConnectionEvent::EndGame)),
+        _ => Err(DolphinConnectionError::PacketError("unexpected packet type")),
     }
 }
 
-pub async fn data_stream(addr: SocketAddr, interrupt_receiver: Receiver<bool>) -> Pin<Box<SlippiDataStream>> {
-    let socket = UdpSocket::bind(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))).unwrap();
+/// Drive the ENet host until the connection ends, sending game data to
+/// `data_sender` and lifecycle events to `lifecycle_sender`. Dolphin's
+/// connection details are sent to `connected_sender` once it replies to the
+/// connection request.
+async fn drive_host(
+    mut dolphin_host: DolphinHost,
+    mut interrupt_receiver: Receiver<bool>,
+    connected_sender: oneshot::Sender<SlippiConnectionInfo>,
+    data_sender: mpsc::Sender<Vec<u8>>,
+    lifecycle_sender: SlippiLifecycleSender
+) {
+    let mut connected_sender = Some(connected_sender);
+
+    // Poll Dolphin connection at 120Hz
+    let mut poll_interval = interval(Duration::from_micros(8333));
+    poll_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
-    let mut host =
-        enet::Host::<UdpSocket>::new(
-            socket,
-            enet::HostSettings {
-                peer_limit: MAX_PEERS,
-                channel_limit: 3,
-                ..Default::default()
-            },
-        )
-        .unwrap();
+    loop {
+        tokio::select! {
+            // A closed interrupt channel is treated the same as an interrupt.
+            _ = interrupt_receiver.next(), if !dolphin_host.interrupted => {
+                dolphin_host.disconnect();
+            }
+            _ = poll_interval.tick() => ()
+        }
 
-    // Initiate connection
-    let peer = host.connect(addr, 3, 1337).unwrap();
-    peer.set_ping_interval(100);
-    let peer_id = peer.id();
+        let events = match dolphin_host.full_service() {
+            Ok(events) => events,
+            Err(e) => {
+                tracing::error!("Error servicing Dolphin connection: {}", e);
+                return;
+            }
+        };
 
-    let mut dolphin_host = DolphinHost { host, interrupt_receiver };
-    dolphin_host = wait_for_connected(dolphin_host, peer_id);
+        let mut game_data: Vec<u8> = Vec::new();
 
-    // Poll Dolphin connection at 120Hz
-    let mut i = interval(Duration::from_micros(8333));
-    let mut dcd = false;
-
-    let (sender, receiver) = std::sync::mpsc::channel::<DolphinHost>();
-    sender.send(dolphin_host).unwrap();
-
-    Box::pin(stream::poll_fn(move |cx: &mut Context<'_>| {
-        if dcd {
-            Poll::Ready(None)
-        } else {
-            let p = i.poll_tick(cx);
-
-            match p {
-                Poll::Pending => Poll::Pending,
-                Poll::Ready(_) => {
-                    let channel_host = receiver.try_recv().unwrap();
-                    match full_service(channel_host, peer_id) {
-                        Result::Err(_e) => Poll::Ready(None),
-                        Result::Ok((new_host, events)) => {
-                            sender.send(new_host).unwrap();
-                            if let Some(ConnectionEvent::Disconnect) = events.last() {
-                                dcd = true;
-                            }
-
-                            cx.waker().clone().wake();
-                            let game_data: Vec<u8> = events
-                                .into_iter()
-                                .map(|event| match event {
-                                    ConnectionEvent::Message { payload } => payload,
-                                    _ => vec![],
-                                })
-                                .flatten()
-                                .collect();
-                            Poll::Ready(Some(game_data))
+        for event in events {
+            match event {
+                ConnectionEvent::Connect { nick, version } => {
+                    if let Some(sender) = connected_sender.take() {
+                        let _ = sender.send(SlippiConnectionInfo { nickname: nick, version, client_token: None });
+                    } else {
+                        tracing::info!("Reconnected to Dolphin.");
+                        let _ = lifecycle_sender.unbounded_send(SlippiLifecycleEvent::Reconnected);
+                    }
+                }
+                ConnectionEvent::Disconnect => {
+                    if dolphin_host.reconnect && !dolphin_host.interrupted && connected_sender.is_none() {
+                        tracing::info!("Dolphin disconnected, reconnecting...");
+                        let _ = lifecycle_sender.unbounded_send(SlippiLifecycleEvent::Disconnected);
+                        if let Err(e) = dolphin_host.reconnect() {
+                            tracing::error!("Error reconnecting to Dolphin: {}", e);
+                            return;
                         }
+                    } else {
+                        return;
                     }
                 }
+                ConnectionEvent::Message { mut payload } => game_data.append(&mut payload),
+                ConnectionEvent::StartGame => {
+                    let _ = lifecycle_sender.unbounded_send(SlippiLifecycleEvent::GameStarted);
+                }
+                ConnectionEvent::EndGame => {
+                    let _ = lifecycle_sender.unbounded_send(SlippiLifecycleEvent::GameEnded);
+                }
             }
         }
-    }))
+
+        if !game_data.is_empty() && data_sender.send(game_data).await.is_err() && !dolphin_host.interrupted {
+            // Nothing is consuming the data stream anymore
+            dolphin_host.disconnect();
+        }
+    }
+}
+
+pub async fn data_stream(
+    addr: SocketAddr,
+    options: DolphinOptions,
+    interrupt_receiver: Receiver<bool>,
+    lifecycle_sender: SlippiLifecycleSender
+) -> Result<(Pin<Box<SlippiDataStream>>, SlippiConnectionInfo), DolphinConnectionError> {
+    let dolphin_host = DolphinHost::new(addr, &options)?;
+
+    let (connected_sender, connected_receiver) = oneshot::channel();
+    let (data_sender, data_receiver) = mpsc::channel(100);
+
+    let driver_task = tokio::spawn(drive_host(dolphin_host, interrupt_receiver, connected_sender, data_sender, lifecycle_sender));
+
+    let connection_info =
+        match timeout(CONNECT_TIMEOUT, connected_receiver).await {
+            Ok(Ok(connection_info)) => connection_info,
+            Ok(Err(_)) => return Err(DolphinConnectionError::ConnectionClosed),
+            Err(_) => {
+                driver_task.abort();
+                return Err(DolphinConnectionError::ConnectTimeout);
+            }
+        };
+
+    Ok((Box::pin(ReceiverStream::new(data_receiver)), connection_info))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_packet_skips_already_received_game_events() {
+        let mut cursor = 0;
+
+        let reply = parse_packet(br#"{"type":"connect_reply","nick":"Slippi Online","version":"3.4.0","cursor":5}"#, &mut cursor).unwrap();
+        assert!(matches!(reply, Some(ConnectionEvent::Connect { nick: Some(_), version: Some(_) })));
+        assert_eq!(cursor, 5);
+
+        let old_event = parse_packet(br#"{"type":"game_event","cursor":4,"next_cursor":5,"payload":"AQI="}"#, &mut cursor).unwrap();
+        assert!(old_event.is_none());
+        assert_eq!(cursor, 5);
+
+        let new_event = parse_packet(br#"{"type":"game_event","cursor":5,"next_cursor":6,"payload":"AQI="}"#, &mut cursor).unwrap();
+        assert!(matches!(new_event, Some(ConnectionEvent::Message { payload }) if payload == vec![1, 2]));
+        assert_eq!(cursor, 6);
+    }
 }
@@ -0,0 +1,221 @@
+use std::{
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    pin::Pin,
+    time::Duration
+};
+use futures::channel::mpsc::Receiver;
+use async_stream::stream;
+use byteorder::{BE, ByteOrder};
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::{
+    broadcast::{SlippiConnectionInfo, SlippiLifecycleEvent, SlippiLifecycleSender},
+    common::SlippiDataStream,
+    slippi::{parse_payloads, Event},
+    spectate::slp_file::RAW_HEADER
+};
+
+/// Melee runs at 60 frames per second.
+const FRAME_DURATION_SECS: f64 = 1.0 / 60.0;
+
+/// The range of replay speeds allowed. Speeds outside of it are clamped, since
+/// frame intervals would otherwise round to zero or overflow.
+pub const MIN_SPEED: f64 = 0.01;
+pub const MAX_SPEED: f64 = 100.0;
+
+/// Read the raw Slippi event bytes from a .slp file, stripping the UBJSON
+/// wrapper and metadata.
+fn read_raw_events(path: &Path) -> std::io::Result<Vec<u8>> {
+    let contents = std::fs::read(path)?;
+
+    if !contents.starts_with(RAW_HEADER) || contents.len() < RAW_HEADER.len() + 4 {
+        return Err(Error::new(ErrorKind::InvalidData, format!("{} is not a .slp file", path.display())));
+    }
+
+    let raw_start = RAW_HEADER.len() + 4;
+    let raw_length = BE::read_u32(&contents[RAW_HEADER.len()..raw_start]) as usize;
+
+    // A raw length of 0 means the file was not finalized, for example because
+    // the game is still in progress. In this case, take everything until EOF.
+    let raw_end =
+        if raw_length == 0 {
+            contents.len()
+        } else {
+            (raw_start + raw_length).min(contents.len())
+        };
+
+    Ok(contents[raw_start..raw_end].to_vec())
+}
+
+/// Split raw Slippi events into chunks, one per game frame. The first chunk
+/// additionally contains everything before the first frame (Event Payloads,
+/// Game Start, and so on), and the last chunk contains Game End.
+///
+/// Frame boundaries are detected from the frame number of Frame Start events,
+/// or Pre-Frame Update events for replays which predate Frame Start.
+///
+/// Also returns whether the replay contains Game End.
+fn split_frames(raw: &[u8]) -> std::io::Result<(Vec<Vec<u8>>, bool)> {
+    let (mut pos, payload_sizes) = parse_payloads(raw)?;
+    let mut frames: Vec<Vec<u8>> = Vec::new();
+    let mut chunk_start = 0;
+    let mut current_frame: Option<i32> = None;
+    let mut game_ended = false;
+
+    while pos < raw.len() {
+        let command = raw[pos];
+        let command_size = payload_sizes.get(&command).ok_or_else(|| {
+            Error::new(ErrorKind::InvalidData, format!("unknown event {:#02x} at position {}", command, pos))
+        })?.to_owned() as usize;
+
+        let event_end = pos + command_size + 1; // include command byte
+        if event_end > raw.len() {
+            // Incomplete trailing event, most likely a file which was still
+            // being written; ignore the rest.
+            break;
+        }
+
+        if command == Event::FrameStart as u8 || command == Event::FramePre as u8 {
+            if command_size < 4 {
+                return Err(Error::new(ErrorKind::InvalidData, format!("event {:#02x} is too small to hold a frame number", command)));
+            }
+
+            let frame = BE::read_i32(&raw[(pos + 1)..(pos + 5)]);
+
+            match current_frame {
+                Some(current) if current != frame => {
+                    frames.push(raw[chunk_start..pos].to_vec());
+                    chunk_start = pos;
+                }
+                _ => ()
+            }
+
+            current_frame = Some(frame);
+        }
+
+        pos = event_end;
+
+        if command == Event::GameEnd as u8 {
+            game_ended = true;
+            break;
+        }
+    }
+
+    frames.push(raw[chunk_start..pos].to_vec());
+    Ok((frames, game_ended))
+}
+
+/// Replay a .slp file as if it were a live Slippi connection. Events are
+/// released one frame at a time in real time, multiplied by `speed`, which is
+/// clamped to between [`MIN_SPEED`] and [`MAX_SPEED`].
+pub async fn data_stream(
+    path: PathBuf,
+    speed: f64,
+    mut interrupt_receiver: Receiver<bool>,
+    lifecycle_sender: SlippiLifecycleSender
+) -> std::io::Result<(Pin<Box<SlippiDataStream>>, SlippiConnectionInfo)> {
+    if !speed.is_finite() {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("invalid replay speed: {}", speed)));
+    }
+    if !(MIN_SPEED..=MAX_SPEED).contains(&speed) {
+        tracing::warn!("Replay speed must be between {} and {}, clamping {}", MIN_SPEED, MAX_SPEED, speed);
+    }
+    let speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+
+    let (frames, game_ended) = read_raw_events(&path).and_then(|raw| split_frames(&raw))?;
+
+    let stream = Box::pin(stream! {
+        let mut frame_interval = interval(Duration::from_secs_f64(FRAME_DURATION_SECS / speed));
+        frame_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut interrupted = false;
+        let _ = lifecycle_sender.unbounded_send(SlippiLifecycleEvent::GameStarted);
+
+        for frame in frames {
+            frame_interval.tick().await;
+
+            match interrupt_receiver.try_next() {
+                Ok(Some(_)) => {
+                    // interrupt was sent
+                    interrupted = true;
+                    break
+                }
+                Ok(None) => {
+                    tracing::error!("Interrupt channel closed unexpectedly");
+                    interrupted = true;
+                    break
+                }
+                _ => ()
+            }
+
+            yield frame;
+        }
+
+        if game_ended && !interrupted {
+            let _ = lifecycle_sender.unbounded_send(SlippiLifecycleEvent::GameEnded);
+        }
+
+        tracing::info!("Finished replaying {}", path.display());
+    });
+
+    Ok((stream, SlippiConnectionInfo::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Event Payloads declaring Game Start (1 byte), Pre-Frame Update (4 bytes),
+    // Game End (1 byte) and Frame Start (4 bytes).
+    const PAYLOADS: [u8; 14] = [0x35, 13, 0x36, 0, 1, 0x37, 0, 4, 0x39, 0, 1, 0x3A, 0, 4];
+
+    fn frame_event(command: u8, frame: i32) -> Vec<u8> {
+        let mut event = vec![command];
+        event.extend_from_slice(&frame.to_be_bytes());
+        event
+    }
+
+    #[test]
+    fn split_frames_groups_events_by_frame() {
+        let mut raw = PAYLOADS.to_vec();
+        raw.extend([0x36, 0]);
+        raw.extend(frame_event(0x3A, -123));
+        raw.extend(frame_event(0x37, -123));
+        raw.extend(frame_event(0x3A, -122));
+        raw.extend(frame_event(0x37, -122));
+        raw.extend([0x39, 0]);
+
+        let (frames, game_ended) = split_frames(&raw).unwrap();
+
+        assert!(game_ended);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].len(), PAYLOADS.len() + 2 + 10);
+        assert_eq!(frames[1], [frame_event(0x3A, -122), frame_event(0x37, -122), vec![0x39, 0]].concat());
+    }
+
+    #[test]
+    fn split_frames_ignores_incomplete_trailing_event() {
+        let mut raw = PAYLOADS.to_vec();
+        raw.extend([0x36, 0]);
+        raw.extend(frame_event(0x37, -123));
+        raw.extend([0x37, 0xFF]);
+
+        let (frames, game_ended) = split_frames(&raw).unwrap();
+
+        assert!(!game_ended);
+        assert_eq!(frames, vec![raw[..(raw.len() - 2)].to_vec()]);
+    }
+
+    #[test]
+    fn split_frames_rejects_undersized_frame_events() {
+        // Event Payloads declaring Game Start (1 byte), Game End (1 byte) and
+        // Frame Start (2 bytes)
+        let mut raw = vec![0x35, 10, 0x36, 0, 1, 0x39, 0, 1, 0x3A, 0, 2];
+        raw.extend([0x36, 0]);
+        raw.extend([0x3A, 0, 0]);
+        raw.extend([0x36, 0]);
+
+        let error = split_frames(&raw).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -1,3 +1,67 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+
+use console_connection::ConsoleOptions;
+use dolphin_connection::DolphinOptions;
+
 pub mod connection_manager;
 pub mod console_connection;
-pub mod dolphin_connection;
\ No newline at end of file
+pub mod dolphin_connection;
+pub mod file_connection;
+
+/// A source of Slippi data which can be broadcast.
+#[derive(Debug, Clone)]
+pub enum SlippiSource {
+    /// A Wii running Nintendont with Slippi.
+    Console(SocketAddr, ConsoleOptions),
+    /// A Slippi Dolphin instance.
+    Dolphin(SocketAddr, DolphinOptions),
+    /// A .slp replay file, replayed in real time multiplied by `speed`.
+    File { path: PathBuf, speed: f64 },
+}
+
+/// Events reported by a Slippi source alongside its game data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SlippiLifecycleEvent {
+    GameStarted,
+    GameEnded,
+    /// The connection to the source was lost, and is being re-established.
+    Disconnected,
+    Reconnected,
+}
+
+pub type SlippiLifecycleSender = UnboundedSender<SlippiLifecycleEvent>;
+pub type SlippiLifecycleReceiver = UnboundedReceiver<SlippiLifecycleEvent>;
+
+/// Details reported by a Slippi source upon connection.
+#[derive(Debug, Clone, Default)]
+pub struct SlippiConnectionInfo {
+    /// The nickname of the console, as set in the Slippi Launcher.
+    pub nickname: Option<String>,
+    /// The version of Nintendont or Dolphin running Slippi.
+    pub version: Option<String>,
+    /// The token a console assigned to this client, which can be used to
+    /// resume the connection.
+    pub client_token: Option<u32>,
+}
+
+impl std::fmt::Display for SlippiConnectionInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.nickname.as_deref().unwrap_or("Slippi"))?;
+        if let Some(version) = &self.version {
+            write!(f, " (version {})", version)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for SlippiSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SlippiSource::Console(addr, _) => write!(f, "console at {}", addr),
+            SlippiSource::Dolphin(addr, _) => write!(f, "Dolphin at {}", addr),
+            SlippiSource::File { path, speed } => write!(f, "replay file {} at {}x speed", path.display(), speed),
+        }
+    }
+}
@@ -1 +1,81 @@
+use crate::{
+    broadcast::SlippiLifecycleEvent,
+    slippi::{Event, EventSplitter}
+};
+
 pub type SlippiDataStream = dyn futures::stream::Stream<Item = Vec<u8>> + Send;
+
+/// Detects game boundaries in raw Slippi data, which is not necessarily split
+/// on event boundaries.
+#[derive(Debug, Default)]
+pub(crate) struct GameTracker {
+    splitter: EventSplitter,
+}
+
+impl GameTracker {
+    /// Process the next chunk of data. Returns the games started and ended
+    /// in it, along with the position of the Event Payloads or Game End event
+    /// in all data processed so far.
+    pub(crate) fn process(&mut self, data: &[u8]) -> Vec<(usize, SlippiLifecycleEvent)> {
+        let mut events = Vec::new();
+
+        self.splitter.process(data, |position, event, _| {
+            if event[0] == Event::Payloads as u8 {
+                events.push((position, SlippiLifecycleEvent::GameStarted));
+            } else if event[0] == Event::GameEnd as u8 {
+                events.push((position, SlippiLifecycleEvent::GameEnded));
+            }
+        });
+
+        events
+    }
+}
+
+/// Everything received for the latest game, starting from its Event Payloads,
+/// to send again to whoever missed it.
+#[derive(Debug, Default)]
+pub(crate) struct LatestGame {
+    data: Vec<u8>,
+    /// Position of the start of `data` in all data pushed.
+    position: usize,
+    game_tracker: GameTracker,
+}
+
+impl LatestGame {
+    pub(crate) fn push(&mut self, data: &[u8]) {
+        self.data.extend_from_slice(data);
+
+        for (position, event) in self.game_tracker.process(data) {
+            if event == SlippiLifecycleEvent::GameStarted {
+                // Only keep the new game
+                self.data.drain(..(position - self.position));
+                self.position = position;
+            }
+        }
+    }
+
+    pub(crate) fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn game_tracker_detects_games_split_across_chunks() {
+        // Event Payloads declaring Game Start (1 byte), Pre-Frame Update
+        // (4 bytes) and Game End (1 byte).
+        let payloads = [0x35, 10, 0x36, 0, 1, 0x37, 0, 4, 0x39, 0, 1];
+        let mut tracker = GameTracker::default();
+
+        assert_eq!(tracker.process(&[0xFF]), vec![]);
+        assert_eq!(tracker.process(&payloads[..4]), vec![]);
+        assert_eq!(tracker.process(&payloads[4..]), vec![(1, SlippiLifecycleEvent::GameStarted)]);
+        assert_eq!(tracker.process(&[0x36, 0, 0x37, 0, 0]), vec![]);
+        assert_eq!(tracker.process(&[0, 0, 0x39]), vec![]);
+        assert_eq!(tracker.process(&[0]), vec![(19, SlippiLifecycleEvent::GameEnded)]);
+        assert!(tracker.splitter.is_empty());
+    }
+}
@@ -3,6 +3,8 @@ use serde::{Deserialize, Serialize};
 use std::{
     fs::{self, File}, io, path::{Path, PathBuf}
 };
+
+use crate::spectate::replay_name::DEFAULT_REPLAY_NAME_TEMPLATE;
 // use thiserror::Error;
 
 // TODO: Change to thiserror definition as appropriate
@@ -83,6 +85,8 @@ struct SlippiLauncherSettings {
 struct SpectateSettings {
     #[serde(rename = "spectate_directory", skip_serializing_if = "Option::is_none")]
     spectate_directory: Option<String>,
+    #[serde(rename = "replay_name_template", skip_serializing_if = "Option::is_none")]
+    replay_name_template: Option<String>,
 }
 
 const SETTINGS_FILE_NAME: &str = "settings.json";
@@ -122,12 +126,19 @@ impl Config {
         self.config_path().join(join_path)
     }
 
-    pub(crate) fn comm_spec_path(&self) -> PathBuf {
+    /// The path of the file Playback Dolphin reads commands from. Each
+    /// `instance` of Playback Dolphin running at once needs its own.
+    pub(crate) fn comm_spec_path(&self, instance: Option<&str>) -> PathBuf {
         let temp_path = self.config_path().join("temp");
         if !temp_path.exists() {
             fs::create_dir(&temp_path).unwrap();
         }
-        let comm_spec_path = temp_path.join("launch.json");
+        let file_name =
+            match instance {
+                Some(instance) => format!("launch-{}.json", instance),
+                None => "launch.json".to_string()
+            };
+        let comm_spec_path = temp_path.join(file_name);
         if !comm_spec_path.exists() {
             File::create(&comm_spec_path).unwrap();
         }
@@ -183,19 +194,7 @@ impl Config {
         let settings_path = self.config_path().join(SETTINGS_FILE_NAME);
 
         // Read existing settings or create empty object
-        let mut settings = if settings_path.exists() {
-            let content = fs::read_to_string(&settings_path)
-                .map_err(|e| ConfigError::FileRead(settings_path.clone(), e))?;
-
-            if content.trim().is_empty() {
-                SpectateSettings::default()
-            } else {
-                serde_json::from_str(&content)
-                    .map_err(|e| ConfigError::JsonParse(settings_path.clone(), e))?
-            }
-        } else {
-            SpectateSettings::default()
-        };
+        let mut settings = self.spectate_settings()?;
 
         // Update the spectate directory
         settings.spectate_directory = Some(dir_path);
@@ -210,27 +209,39 @@ impl Config {
         Ok(())
     }
 
+    /// Read swb's settings file, which may not exist yet.
+    fn spectate_settings(&self) -> Result<SpectateSettings, ConfigError> {
+        let settings_path = self.config_path().join(SETTINGS_FILE_NAME);
+
+        if !settings_path.exists() {
+            return Ok(SpectateSettings::default());
+        }
+
+        let content = fs::read_to_string(&settings_path)
+            .map_err(|e| ConfigError::FileRead(settings_path.clone(), e))?;
+
+        if content.trim().is_empty() {
+            Ok(SpectateSettings::default())
+        } else {
+            serde_json::from_str(&content)
+                .map_err(|e| ConfigError::JsonParse(settings_path, e))
+        }
+    }
+
+    /// Fetch the template for naming replay files, relative to the directory
+    /// they are saved in. See [`crate::spectate::replay_name`] for the
+    /// placeholders available.
+    pub(crate) fn get_replay_name_template(&self) -> Result<String, ConfigError> {
+        let settings = self.spectate_settings()?;
+        Ok(settings.replay_name_template.unwrap_or(DEFAULT_REPLAY_NAME_TEMPLATE.to_string()))
+    }
+
     /// Fetch the path to download replays to which are being spectated.
     /// If not explicitly set, defaults to rootSlpPath + "Spectate" from Slippi Launcher settings
     /// and saves this default to the settings file.
     pub(crate) fn get_spectate_replay_directory_path(&self) -> Result<PathBuf, ConfigError> {
-        let settings_path = self.config_path().join(SETTINGS_FILE_NAME);
-
         // Try to read existing spectate settings
-        let maybe_spectate_directory = if settings_path.exists() {
-            let content = fs::read_to_string(&settings_path)
-                .map_err(|e| ConfigError::FileRead(settings_path.clone(), e))?;
-
-            if !content.trim().is_empty() {
-                let settings: SpectateSettings = serde_json::from_str(&content)
-                    .map_err(|e| ConfigError::JsonParse(settings_path, e))?;
-                settings.spectate_directory
-            } else {
-                None
-            }
-        } else {
-            None
-        };
+        let maybe_spectate_directory = self.spectate_settings()?.spectate_directory;
 
         let spectate_directory =
             if let Some(dir) = maybe_spectate_directory {
@@ -1,10 +1,13 @@
 use std::io::Write;
-use std::net::{AddrParseError, SocketAddr};
+use std::net::AddrParseError;
+use std::path::PathBuf;
 use std::pin::Pin;
+use std::sync::Arc;
 
-use futures::StreamExt;
-use futures::{channel::mpsc::channel};
+use futures::{future, StreamExt};
+use futures::channel::mpsc::{channel, unbounded};
 use thiserror::Error;
+use tokio::task::JoinSet;
 use tokio_util::sync::CancellationToken;
 
 use crate::common::SlippiDataStream;
@@ -12,8 +15,12 @@ use crate::common::SlippiDataStream;
 pub mod broadcast;
 pub mod spectate;
 pub mod spectator_mode_client;
+pub mod spectator_mode_server;
 pub mod common;
 pub mod config;
+pub mod overlay;
+pub mod slippi;
+pub mod stats;
 
 #[derive(Error, Debug)]
 pub enum SwbError {
@@ -29,25 +36,55 @@ pub enum SwbError {
     #[error("Unknown source scheme: {0}")]
     UnknownSourceScheme(String),
 
+    #[error("Invalid source: {0}")]
+    InvalidSource(String),
+
+    #[error("Console connection error: {0}")]
+    ConsoleConnectionError(#[from] broadcast::console_connection::ConsoleCommunicationError),
+
+    #[error("Dolphin connection error: {0}")]
+    DolphinConnectionError(#[from] broadcast::dolphin_connection::DolphinConnectionError),
+
+    #[error("Error reading replay file: {0}")]
+    ReplayFileError(std::io::Error),
+
+    #[error("Error writing replay file: {0}")]
+    ReplayWriteError(std::io::Error),
+
     #[error("SpectatorMode connection error: {0}")]
     SpectatorModeClientError(#[from] spectator_mode_client::SpectatorModeClientError),
 
+    #[error("Error starting overlay server: {0}")]
+    OverlayServerError(std::io::Error),
+
+    #[error("Local server error: {0}")]
+    SpectatorModeServerError(#[from] spectator_mode_server::SpectatorModeServerError),
+
+
     #[error("WebSocket error: {0}")]
     TungsteniteError(#[from] tokio_tungstenite::tungstenite::Error)
 }
 
-pub async fn connect_to_slippi(source_addr: SocketAddr, is_console: bool) -> (Pin<Box<SlippiDataStream>>, impl FnMut()) {
+pub async fn connect_to_slippi(source: SlippiSource) -> Result<(Pin<Box<SlippiDataStream>>, impl FnMut(), SlippiConnectionInfo, SlippiLifecycleReceiver), SwbError> {
     let (sender, receiver) = channel::<bool>(100);
     let mut other_sender = sender.clone();
+    let (lifecycle_sender, lifecycle_receiver) = unbounded();
 
-    tracing::info!("Connecting to Slippi {} at {}...", if is_console { "console" } else { "Dolphin" }, source_addr);
-    let conn  =
-        if is_console {
-            broadcast::console_connection::data_stream(source_addr, receiver).await
-        } else {
-            broadcast::dolphin_connection::data_stream(source_addr, receiver).await
+    tracing::info!("Connecting to Slippi {}...", source);
+    let (conn, connection_info) =
+        match source {
+            SlippiSource::Console(source_addr, options) => {
+                broadcast::console_connection::data_stream(source_addr, options, receiver, lifecycle_sender).await?
+            }
+            SlippiSource::Dolphin(source_addr, options) => {
+                broadcast::dolphin_connection::data_stream(source_addr, options, receiver, lifecycle_sender).await?
+            }
+            SlippiSource::File { path, speed } => {
+                broadcast::file_connection::data_stream(path, speed, receiver, lifecycle_sender).await
+                    .map_err(SwbError::ReplayFileError)?
+            }
         };
-    tracing::info!("Connected to Slippi.");
+    tracing::info!("Connected to {}.", connection_info);
 
     let interruptor_to_return = move || {
         match other_sender.try_send(true) {
@@ -56,22 +93,102 @@ pub async fn connect_to_slippi(source_addr: SocketAddr, is_console: bool) -> (Pi
         }
     };
 
-    (conn, interruptor_to_return)
+    Ok((conn, interruptor_to_return, connection_info, lifecycle_receiver))
 }
 
-pub async fn mirror_to_dolphin(stream_url: &str) -> Result<(), SwbError> {
-    let stream_conn = spectate::websocket_connection::data_stream(stream_url).await?;
-    let (mut playback_writer, dolphin_process) = spectate::slp_file_writer::SlpFileWriter::new(true)?;
+/// The SpectatorMode stream ID in a stream URL, if it has one.
+fn stream_id_from_url(stream_url: &str) -> Option<u32> {
+    let url = url::Url::parse(stream_url).ok()?;
+    url.query_pairs()
+        .find(|(key, _)| key == "stream_id")
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+/// Save the games from a stream to .slp files in `output_directory`, without
+/// launching Playback Dolphin. Files are named according to
+/// `replay_name_template`, as described in [`spectate::replay_name`], or
+/// `Game_{timestamp}` by default. Returns once the stream ends; a game still
+/// in progress is saved as far as it was received.
+pub async fn record(stream_url: &str, output_directory: PathBuf, replay_name_template: Option<&str>) -> Result<(), SwbError> {
+    let mut stream_conn = spectate::websocket_connection::data_stream(stream_url).await?;
+    let mut writer = spectate::slp_file_writer::SlpFileWriter::with_directory(
+        output_directory,
+        stream_id_from_url(stream_url),
+        replay_name_template.unwrap_or(spectate::replay_name::DEFAULT_REPLAY_NAME_TEMPLATE)
+    );
+
+    while let Some(data) = stream_conn.next().await {
+        writer.write_all(&data).map_err(SwbError::ReplayWriteError)?;
+    }
+
+    tracing::info!("Stream ended; finished recording.");
+    Ok(())
+}
+
+/// Watch one or more streams, each in its own Playback Dolphin. When watching
+/// several, each stream's replays are saved in a subdirectory of the spectate
+/// directory, named after its stream ID if it has one. Returns once every
+/// stream is done; if any failed, the first error is returned.
+pub async fn mirror_to_dolphin(stream_urls: &[String], options: spectate::SpectateOptions) -> Result<(), SwbError> {
+    let instances = mirror_instance_names(stream_urls);
+
+    let results = future::join_all(stream_urls.iter().zip(instances).map(|(stream_url, instance)| {
+        mirror_stream_to_dolphin(stream_url, instance, options.clone())
+    })).await;
+
+    for (stream_url, result) in stream_urls.iter().zip(&results) {
+        if let Err(e) = result {
+            tracing::error!("Error watching {}: {}", stream_url, e);
+        }
+    }
+
+    results.into_iter().collect()
+}
+
+/// Names telling apart the Playback Dolphin instances watching each stream,
+/// or `None` if there is only one.
+fn mirror_instance_names(stream_urls: &[String]) -> Vec<Option<String>> {
+    if stream_urls.len() == 1 {
+        return vec![None];
+    }
+
+    let stream_ids: Vec<Option<u32>> = stream_urls.iter().map(|stream_url| stream_id_from_url(stream_url)).collect();
+
+    stream_ids.iter().enumerate().map(|(i, stream_id)| {
+        match stream_id {
+            Some(stream_id) if stream_ids.iter().filter(|id| *id == &Some(*stream_id)).count() == 1 => {
+                Some(stream_id.to_string())
+            }
+            _ => Some(format!("stream-{}", i + 1))
+        }
+    }).collect()
+}
+
+async fn mirror_stream_to_dolphin(stream_url: &str, instance: Option<String>, options: spectate::SpectateOptions) -> Result<(), SwbError> {
+    let mut stream_conn = spectate::websocket_connection::data_stream(stream_url).await?;
+    let (mut playback_writer, dolphin_process) =
+        spectate::slp_file_writer::SlpFileWriter::new_instance(true, stream_id_from_url(stream_url), instance.as_deref())?;
+    let mut game_state_tracker = options.overlay.map(|overlay| overlay.track(stream_id_from_url(stream_url)));
+
+    if let Some(playback) = options.playback {
+        stream_conn = spectate::playback_delay::delay_playback(stream_conn, playback);
+    }
 
     let token = CancellationToken::new();
     let cloned_token_1 = token.clone();
     let cloned_token_2 = token.clone();
 
+    let writer_stream_url = stream_url.to_string();
     let writer_task = tokio::spawn(async move {
         let writer_future =
             stream_conn.map(|data| {
-                // This is assuming that no events are split between stream items
-                playback_writer.write_all(&data).unwrap();
+                if let Err(e) = playback_writer.write_all(&data) {
+                    tracing::warn!("Error writing replay for {}: {}", writer_stream_url, e);
+                }
+
+                if let Some(tracker) = &mut game_state_tracker {
+                    tracker.process(&data);
+                }
             }).collect::<()>();
 
         tokio::select! {
@@ -110,10 +227,106 @@ pub async fn mirror_to_dolphin(stream_url: &str) -> Result<(), SwbError> {
     };
 
     tokio::join!(wrapped_writer_future, wrapped_dolphin_future);
-    println!("Both tasks are done");
+
+    Ok(())
+}
+
+/// Watch several streams in one Playback Dolphin, switching to whichever
+/// stream most recently started a game. Every game is saved, in a
+/// subdirectory of the spectate directory per stream as with
+/// [`mirror_to_dolphin`].
+pub async fn follow_in_dolphin(stream_urls: &[String], options: spectate::SpectateOptions) -> Result<(), SwbError> {
+    let mut stream_conns = Vec::new();
+    for stream_url in stream_urls {
+        stream_conns.push(spectate::websocket_connection::data_stream(stream_url).await?);
+    }
+
+    let (playback_dolphin, mut dolphin_process) = spectate::playback_dolphin::PlaybackDolphin::launch(None)?;
+    let playback_dolphin = Arc::new(playback_dolphin);
+    let mut writer_tasks = JoinSet::new();
+
+    for ((stream_url, instance), mut stream_conn) in stream_urls.iter().zip(mirror_instance_names(stream_urls)).zip(stream_conns) {
+        let stream_id = stream_id_from_url(stream_url);
+        let mut writer = spectate::slp_file_writer::SlpFileWriter::with_playback_dolphin(
+            Arc::clone(&playback_dolphin),
+            stream_id,
+            instance.as_deref()
+        )?;
+        let mut game_state_tracker = options.overlay.as_ref().map(|overlay| overlay.track(stream_id));
+
+        if let Some(playback) = &options.playback {
+            stream_conn = spectate::playback_delay::delay_playback(stream_conn, playback.clone());
+        }
+
+        let stream_url = stream_url.clone();
+        writer_tasks.spawn(async move {
+            while let Some(data) = stream_conn.next().await {
+                if let Err(e) = writer.write_all(&data) {
+                    tracing::warn!("Error writing replay for {}: {}", stream_url, e);
+                }
+
+                if let Some(tracker) = &mut game_state_tracker {
+                    tracker.process(&data);
+                }
+            }
+
+            tracing::info!("Stream {} exited.", stream_url);
+        });
+    }
+
+    tokio::select! {
+        _ = async { while writer_tasks.join_next().await.is_some() {} } => {
+            tracing::info!("All streams exited; finished writing.");
+            if let Err(e) = dolphin_process.kill() {
+                tracing::warn!("Error closing Playback Dolphin: {}", e);
+            }
+        }
+        result = dolphin_process.status() => {
+            tracing::info!("Dolphin closed with result {:?}", result);
+        }
+    }
 
     Ok(())
 }
 
 pub use spectator_mode_client::initiate_spectatormode_connection;
-pub use broadcast::connection_manager::forward_streams;
+pub use spectate::{playback_delay::PlaybackControl, SpectateOptions};
+pub use spectator_mode_server::{relay, serve};
+pub use broadcast::connection_manager::{forward_streams, BroadcastDelay, ForwardOptions};
+pub use broadcast::{SlippiConnectionInfo, SlippiLifecycleEvent, SlippiLifecycleReceiver, SlippiSource};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn urls(urls: &[&str]) -> Vec<String> {
+        urls.iter().map(|url| url.to_string()).collect()
+    }
+
+    #[test]
+    fn mirror_instance_names_tell_streams_apart() {
+        // A single stream uses the default instance
+        assert_eq!(mirror_instance_names(&urls(&["ws://localhost/viewer_socket/websocket?stream_id=1"])), vec![None]);
+
+        // Streams are named by ID where that is unique, and by position otherwise
+        assert_eq!(
+            mirror_instance_names(&urls(&[
+                "ws://localhost/viewer_socket/websocket?stream_id=3",
+                "ws://localhost/viewer_socket/websocket?stream_id=7&full_replay=true",
+            ])),
+            vec![Some("3".to_string()), Some("7".to_string())]
+        );
+        assert_eq!(
+            mirror_instance_names(&urls(&[
+                "ws://localhost/viewer_socket/websocket?stream_id=3",
+                "ws://remote/viewer_socket/websocket?stream_id=3",
+                "ws://localhost/viewer_socket/websocket?stream_id=4",
+            ])),
+            vec![Some("stream-1".to_string()), Some("stream-2".to_string()), Some("4".to_string())]
+        );
+        assert_eq!(
+            mirror_instance_names(&urls(&["ws://localhost:8080/", "ws://localhost/viewer_socket/websocket?stream_id=2"])),
+            vec![Some("stream-1".to_string()), Some("2".to_string())]
+        );
+    }
+}
@@ -0,0 +1,421 @@
+//! A local HTTP and WebSocket server publishing the state of the games being
+//! broadcast or spectated as JSON, for use in stream overlays such as OBS
+//! browser sources.
+//!
+//! - `GET /state` responds with the current game state.
+//! - A WebSocket connection to `/state` receives the current game state, and
+//!   then the new state every time it changes.
+//!
+//! Both accept a `stream_id` query parameter to choose a stream when more than
+//! one is being broadcast; otherwise the stream with the lowest ID is used.
+
+use std::{collections::BTreeMap, net::SocketAddr, str::FromStr};
+
+use futures::{SinkExt, StreamExt};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::watch
+};
+use tokio_tungstenite::{
+    accept_hdr_async,
+    tungstenite::{
+        handshake::server::{ErrorResponse, Request as WebSocketRequest, Response},
+        http::StatusCode,
+        Message
+    }
+};
+use url::Url;
+
+use crate::slippi::{self, EventParser, GameStart, SlippiEvent};
+
+/// The largest HTTP request header accepted.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PlayerState {
+    /// Port number, from 1 to 4.
+    pub port: u8,
+    /// External character ID.
+    pub character: u8,
+    pub character_name: Option<&'static str>,
+    pub display_name: Option<String>,
+    pub connect_code: Option<String>,
+    pub stocks: u8,
+    pub percent: f32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct GameState {
+    pub stream_id: Option<u32>,
+    /// Whether a game is in progress. After a game ends, its final state is
+    /// kept until the next game starts.
+    pub in_game: bool,
+    pub stage: Option<u16>,
+    pub stage_name: Option<&'static str>,
+    pub frame: Option<i32>,
+    pub players: Vec<PlayerState>,
+}
+
+impl GameState {
+    fn start_game(&mut self, game_start: &GameStart) {
+        self.in_game = true;
+        self.stage = Some(game_start.stage);
+        self.stage_name = slippi::stage_name(game_start.stage);
+        self.frame = None;
+        self.players = game_start.players.iter().map(|player| PlayerState {
+            port: player.port + 1,
+            character: player.character,
+            character_name: slippi::character_name(player.character),
+            display_name: player.display_name.clone(),
+            connect_code: player.connect_code.clone(),
+            stocks: player.stocks,
+            percent: 0.0,
+        }).collect();
+    }
+
+    /// Update the state from an event. Returns whether the state changed.
+    fn read_event(&mut self, event: &SlippiEvent) -> bool {
+        match event {
+            SlippiEvent::GameStart(game_start) => {
+                self.start_game(game_start);
+                true
+            }
+            SlippiEvent::PostFrameUpdate(post_frame) if !post_frame.is_follower => {
+                let frame_changed = self.frame != Some(post_frame.frame);
+                self.frame = Some(post_frame.frame);
+
+                match self.players.iter_mut().find(|player| player.port == post_frame.port + 1) {
+                    Some(player) if player.stocks != post_frame.stocks || player.percent != post_frame.percent => {
+                        player.stocks = post_frame.stocks;
+                        player.percent = post_frame.percent;
+                        true
+                    }
+                    _ => frame_changed
+                }
+            }
+            SlippiEvent::GameEnd(_) => {
+                self.in_game = false;
+                true
+            }
+            _ => false
+        }
+    }
+}
+
+/// The game states of every stream, shared between the streams being
+/// tracked and the overlay server.
+#[derive(Debug, Clone)]
+pub struct OverlayFeed {
+    states: watch::Sender<BTreeMap<Option<u32>, GameState>>,
+}
+
+impl OverlayFeed {
+    /// Track the game state of a stream. `stream_id` should be unique among
+    /// the streams tracked by this feed.
+    pub fn track(&self, stream_id: Option<u32>) -> GameStateTracker {
+        self.states.send_modify(|states| {
+            states.insert(stream_id, GameState { stream_id, ..Default::default() });
+        });
+
+        GameStateTracker {
+            feed: self.clone(),
+            stream_id,
+            parser: EventParser::new(),
+            state: GameState { stream_id, ..Default::default() },
+        }
+    }
+}
+
+/// Updates the game state of one stream in an [`OverlayFeed`] from its raw
+/// Slippi data.
+pub struct GameStateTracker {
+    feed: OverlayFeed,
+    stream_id: Option<u32>,
+    parser: EventParser,
+    state: GameState,
+}
+
+impl GameStateTracker {
+    /// Process the next chunk of data, which does not need to be split on
+    /// event boundaries.
+    pub fn process(&mut self, data: &[u8]) {
+        let mut changed = false;
+        for event in self.parser.process(data) {
+            changed |= self.state.read_event(&event);
+        }
+
+        // Publishing once per chunk rather than per event keeps updates to
+        // about one per frame.
+        if changed {
+            self.feed.states.send_modify(|states| {
+                states.insert(self.stream_id, self.state.clone());
+            });
+        }
+    }
+}
+
+/// Start the overlay server on `addr`. Returns once the server is listening;
+/// it then runs in the background for as long as the runtime does.
+pub async fn start(addr: SocketAddr) -> std::io::Result<OverlayFeed> {
+    let listener = TcpListener::bind(addr).await?;
+    let (states, _) = watch::channel(BTreeMap::new());
+    let feed = OverlayFeed { states };
+    tracing::info!("Serving overlay game state at http://{}/state", listener.local_addr()?);
+
+    let server_feed = feed.clone();
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer_addr)) => {
+                    let states = server_feed.states.subscribe();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_connection(stream, states).await {
+                            tracing::debug!("Overlay connection from {} closed with error: {}", peer_addr, e);
+                        }
+                    });
+                }
+                Err(e) => tracing::warn!("Error accepting overlay connection: {}", e)
+            }
+        }
+    });
+
+    Ok(feed)
+}
+
+/// A parsed HTTP request header.
+#[derive(Debug, PartialEq)]
+struct Request {
+    target: Target,
+    /// Whether the client asked to upgrade to a WebSocket connection.
+    upgrade: bool,
+}
+
+/// What a request asks for.
+#[derive(Debug, PartialEq)]
+struct Target {
+    path: String,
+    stream_id: Option<u32>,
+}
+
+fn parse_target(uri: &str) -> Result<Target, String> {
+    let url = Url::parse(&format!("http://localhost{}", uri)).map_err(|e| e.to_string())?;
+    let stream_id =
+        match url.query_pairs().find(|(key, _)| key == "stream_id") {
+            Some((_, value)) => Some(u32::from_str(&value).map_err(|_| format!("invalid stream_id: {}", value))?),
+            None => None
+        };
+
+    Ok(Target { path: url.path().to_string(), stream_id })
+}
+
+fn parse_request(header: &str) -> Result<Request, String> {
+    let mut lines = header.lines();
+    let request_line = lines.next().ok_or("empty request")?;
+
+    let uri =
+        match request_line.split_whitespace().collect::<Vec<&str>>()[..] {
+            ["GET", uri, _version] => uri,
+            _ => return Err(format!("unsupported request: {}", request_line))
+        };
+
+    // The rest of the upgrade request is validated by the WebSocket handshake.
+    let upgrade = lines
+        .filter_map(|line| line.split_once(':'))
+        .any(|(name, value)| name.trim().eq_ignore_ascii_case("upgrade") && value.trim().eq_ignore_ascii_case("websocket"));
+
+    Ok(Request { target: parse_target(uri)?, upgrade })
+}
+
+/// The JSON for the state of the chosen stream.
+fn state_json(states: &BTreeMap<Option<u32>, GameState>, stream_id: Option<u32>) -> String {
+    let state =
+        match stream_id {
+            Some(stream_id) => states.get(&Some(stream_id)),
+            None => states.values().next()
+        };
+
+    serde_json::to_string(&state.cloned().unwrap_or(GameState { stream_id, ..Default::default() })).unwrap()
+}
+
+async fn write_response(stream: &mut TcpStream, status: &str, content_type: &str, body: &str) -> std::io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nAccess-Control-Allow-Origin: *\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+        status, content_type, body.len(), body
+    );
+    stream.write_all(response.as_bytes()).await
+}
+
+async fn handle_connection(mut stream: TcpStream, states: watch::Receiver<BTreeMap<Option<u32>, GameState>>) -> std::io::Result<()> {
+    // Clients do not send anything after the header until they get a
+    // response, so reading past it is not a concern.
+    let mut buf = Vec::new();
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() >= MAX_REQUEST_SIZE {
+            return write_response(&mut stream, "431 Request Header Fields Too Large", "text/plain", "Request too large").await;
+        }
+
+        let mut chunk = [0; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let request =
+        match parse_request(&String::from_utf8_lossy(&buf)) {
+            Ok(request) => request,
+            Err(e) => return write_response(&mut stream, "400 Bad Request", "text/plain", &e).await
+        };
+
+    if request.upgrade {
+        // Hand the header back to the WebSocket handshake, which reads the
+        // request again and checks it properly.
+        let (read_half, write_half) = stream.into_split();
+        let stream = tokio::io::join(std::io::Cursor::new(buf).chain(read_half), write_half);
+        return serve_websocket(stream, states).await.map_err(std::io::Error::other);
+    }
+
+    if request.target.path != "/state" {
+        return write_response(&mut stream, "404 Not Found", "text/plain", "Not found").await;
+    }
+
+    let body = state_json(&states.borrow(), request.target.stream_id);
+    write_response(&mut stream, "200 OK", "application/json", &body).await
+}
+
+/// Complete a WebSocket handshake, and then send the state of the chosen
+/// stream every time it changes.
+async fn serve_websocket<S>(stream: S, mut states: watch::Receiver<BTreeMap<Option<u32>, GameState>>) -> Result<(), tokio_tungstenite::tungstenite::Error>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin
+{
+    let mut stream_id = None;
+
+    // The error response type is determined by tungstenite
+    #[allow(clippy::result_large_err)]
+    let select_stream = |request: &WebSocketRequest, response: Response| {
+        let (status, message) =
+            match parse_target(&request.uri().to_string()) {
+                Ok(target) if target.path == "/state" => {
+                    stream_id = target.stream_id;
+                    return Ok(response);
+                }
+                Ok(_) => (StatusCode::NOT_FOUND, "Not found".to_string()),
+                Err(e) => (StatusCode::BAD_REQUEST, e)
+            };
+
+        let mut error_response = ErrorResponse::new(Some(message));
+        *error_response.status_mut() = status;
+        Err(error_response)
+    };
+
+    let mut ws_stream = accept_hdr_async(stream, select_stream).await?;
+    let mut last_sent = String::new();
+
+    loop {
+        let json = state_json(&states.borrow_and_update(), stream_id);
+        if json != last_sent {
+            ws_stream.send(Message::text(json.clone())).await?;
+            last_sent = json;
+        }
+
+        tokio::select! {
+            changed = states.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+            }
+            message = ws_stream.next() => {
+                match message {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    _ => ()
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::slippi::{GameEnd, GameEndMethod, Player, PlayerType, PostFrameUpdate};
+
+    #[test]
+    fn game_state_follows_events() {
+        let player = |port: u8, character: u8| Player {
+            port,
+            character,
+            player_type: PlayerType::Human,
+            stocks: 4,
+            costume: 0,
+            team: None,
+            display_name: None,
+            connect_code: Some(format!("P#{}", port)),
+        };
+        let game_start = GameStart {
+            version: (3, 16, 0),
+            is_teams: false,
+            stage: 31,
+            players: vec![player(0, 2), player(1, 9)],
+            random_seed: 0,
+            is_pal: Some(false),
+            is_frozen_ps: Some(false),
+        };
+        let post_frame = PostFrameUpdate {
+            frame: 100,
+            port: 1,
+            is_follower: false,
+            internal_character: 0x12,
+            action_state: 0,
+            x: 0.0,
+            y: 0.0,
+            facing_direction: 1.0,
+            percent: 42.5,
+            shield_size: 60.0,
+            last_attack_landed: 0,
+            combo_count: 0,
+            last_hit_by: 0,
+            stocks: 3,
+            action_state_frame: None,
+            is_airborne: None,
+            jumps_remaining: None,
+            l_cancel_status: None,
+        };
+
+        let mut state = GameState::default();
+        assert!(state.read_event(&SlippiEvent::GameStart(game_start)));
+        assert!(state.read_event(&SlippiEvent::PostFrameUpdate(post_frame.clone())));
+        assert!(!state.read_event(&SlippiEvent::PostFrameUpdate(post_frame)));
+
+        assert!(state.in_game);
+        assert_eq!(state.stage_name, Some("Battlefield"));
+        assert_eq!(state.frame, Some(100));
+        assert_eq!(state.players[0].percent, 0.0);
+        assert_eq!(state.players[1].character_name, Some("Marth"));
+        assert_eq!(state.players[1].stocks, 3);
+        assert_eq!(state.players[1].percent, 42.5);
+
+        let game_end = GameEnd { method: GameEndMethod::Game, lras_initiator: None, placements: None };
+        assert!(state.read_event(&SlippiEvent::GameEnd(game_end)));
+        assert!(!state.in_game);
+        assert_eq!(state.players.len(), 2);
+    }
+
+    #[test]
+    fn parse_request_reads_stream_id_and_upgrade() {
+        let header = "GET /state?stream_id=7 HTTP/1.1\r\nHost: localhost\r\nupgrade: WebSocket\r\n\r\n";
+        assert_eq!(
+            parse_request(header),
+            Ok(Request { target: Target { path: "/state".to_string(), stream_id: Some(7) }, upgrade: true })
+        );
+        assert_eq!(parse_request("GET /state HTTP/1.1\r\n\r\n").map(|request| request.upgrade), Ok(false));
+
+        assert!(parse_request("POST /state HTTP/1.1\r\n\r\n").is_err());
+        assert!(parse_request("GET /state?stream_id=x HTTP/1.1\r\n\r\n").is_err());
+    }
+}
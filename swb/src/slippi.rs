@@ -0,0 +1,692 @@
+//! Decoding of Slippi events, as described by the
+//! [replay spec](https://github.com/project-slippi/slippi-wiki/blob/master/SPEC.md).
+//!
+//! The size of every event is given by Event Payloads at the start of each
+//! game, so events unknown to this module can be skipped over, and events with
+//! more fields than this module knows about still decode. Fields which were
+//! added after an event was introduced are `None` when the game was recorded by
+//! a Slippi version which did not have them yet.
+
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind, Read}
+};
+
+use byteorder::{BE, ByteOrder, ReadBytesExt};
+use serde::Serialize;
+use thiserror::Error;
+
+pub type PayloadSizes = HashMap<u8, u16>;
+
+#[repr(u8)]
+pub enum Event {
+    MessageSplitter = 0x10,
+    Payloads = 0x35,
+    GameStart = 0x36,
+    FramePre = 0x37,
+    FramePost = 0x38,
+    GameEnd = 0x39,
+    FrameStart = 0x3A,
+    Item = 0x3B,
+    FrameEnd = 0x3C,
+    GeckoCodes = 0x3D,
+    FodPlatform = 0x3F,
+    DreamlandWhispy = 0x40,
+    StadiumTransformation = 0x41,
+}
+
+// https://github.com/hohav/peppi/blob/aae5bd380fb6660d846797b19dd66dd232b5b04c/src/io/slippi/de.rs#L531
+
+/// Parses an Event Payloads event from `r`, which must come first in the raw
+/// stream and tells us the sizes for all other events to follow.
+///
+/// Returns the number of bytes read, and a map of event codes to payload sizes.
+/// This map uses raw event codes as keys (as opposed to `Event` enum values)
+/// for forwards compatibility, to allow skipping unknown events.
+pub fn parse_payloads<R: Read>(mut r: R) -> std::io::Result<(usize, PayloadSizes)> {
+    let code = r.read_u8()?;
+    if code != Event::Payloads as u8 {
+        return Err(Error::new(ErrorKind::Other, format!("expected event payloads, but got: {:#02x}", code)));
+    }
+
+    // Size in bytes of the subsequent list of payload-size kv pairs.
+    // Each pair is 3 bytes, so this size should be divisible by 3.
+    // However the value includes this size byte itself, so it's off-by-one.
+    let size = r.read_u8()?;
+    if size % 3 != 1 {
+        return Err(Error::new(ErrorKind::Other, format!("invalid payload size: {}", size)));
+    }
+
+    let mut buf = vec![0; (size - 1) as usize];
+    r.read_exact(&mut buf)?;
+    let buf = &mut &buf[..];
+
+    let mut sizes: PayloadSizes = HashMap::new();
+    for _ in (0..size - 1).step_by(3) {
+        let code = buf.read_u8()?;
+        let size = buf.read_u16::<BE>()?;
+        sizes.insert(code, size);
+    }
+
+    sizes
+        .get(&(Event::GameStart as u8))
+        .ok_or_else(|| Error::new(ErrorKind::Other, "missing Game Start in payload sizes"))?;
+    sizes
+        .get(&(Event::GameEnd as u8))
+        .ok_or_else(|| Error::new(ErrorKind::Other, "missing Game End in payload sizes"))?;
+
+    Ok((1 + size as usize, sizes)) // +1 byte for the event code
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum SlippiParseError {
+    #[error("Expected event {expected:#04x}, but got {actual:#04x}")]
+    UnexpectedEvent { expected: u8, actual: u8 },
+
+    #[error("Event {event:#04x} is too short, expected at least {expected} bytes but got {actual}")]
+    TooShort { event: u8, expected: usize, actual: usize },
+}
+
+fn read_u8(event: &[u8], offset: usize) -> Option<u8> {
+    event.get(offset).copied()
+}
+
+fn read_i8(event: &[u8], offset: usize) -> Option<i8> {
+    read_u8(event, offset).map(|b| b as i8)
+}
+
+fn read_bool(event: &[u8], offset: usize) -> Option<bool> {
+    read_u8(event, offset).map(|b| b != 0)
+}
+
+fn read_u16(event: &[u8], offset: usize) -> Option<u16> {
+    event.get(offset..(offset + 2)).map(BE::read_u16)
+}
+
+fn read_u32(event: &[u8], offset: usize) -> Option<u32> {
+    event.get(offset..(offset + 4)).map(BE::read_u32)
+}
+
+fn read_i32(event: &[u8], offset: usize) -> Option<i32> {
+    event.get(offset..(offset + 4)).map(BE::read_i32)
+}
+
+fn read_f32(event: &[u8], offset: usize) -> Option<f32> {
+    event.get(offset..(offset + 4)).map(BE::read_f32)
+}
+
+/// Check that `event` is of type `expected`, and long enough to contain the
+/// fields every version of it has.
+fn check_event(event: &[u8], expected: Event, min_length: usize) -> Result<(), SlippiParseError> {
+    let expected = expected as u8;
+
+    match event.first() {
+        Some(&actual) if actual != expected => Err(SlippiParseError::UnexpectedEvent { expected, actual }),
+        _ if event.len() < min_length => {
+            Err(SlippiParseError::TooShort { event: expected, expected: min_length, actual: event.len() })
+        }
+        _ => Ok(())
+    }
+}
+
+/// Decode a null-terminated Shift JIS string. Only the ASCII range and the
+/// full-width `#` used in connect codes are supported; other characters are
+/// replaced.
+fn decode_shift_jis(bytes: &[u8]) -> String {
+    let mut decoded = String::new();
+    let mut iter = bytes.iter().take_while(|&&b| b != 0).peekable();
+
+    while let Some(&b) = iter.next() {
+        match b {
+            0x81 if iter.peek() == Some(&&0x94) => {
+                iter.next();
+                decoded.push('#');
+            }
+            0x20..=0x7E => decoded.push(b as char),
+            // First byte of a double-byte character
+            0x81..=0x9F | 0xE0..=0xFC => {
+                iter.next();
+                decoded.push(char::REPLACEMENT_CHARACTER);
+            }
+            _ => decoded.push(char::REPLACEMENT_CHARACTER)
+        }
+    }
+
+    decoded
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlayerType {
+    Human,
+    Cpu,
+    Demo,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Player {
+    /// Port index, where 0 is port 1.
+    pub port: u8,
+    /// External character ID, as used on the character select screen.
+    pub character: u8,
+    pub player_type: PlayerType,
+    pub stocks: u8,
+    pub costume: u8,
+    /// Team ID, if the game is a teams game.
+    pub team: Option<u8>,
+    /// Slippi Online display name (3.9.0).
+    pub display_name: Option<String>,
+    /// Slippi Online connect code, such as `ABC#123` (3.9.0).
+    pub connect_code: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GameStart {
+    /// Slippi version which recorded the game, as major, minor and build.
+    pub version: (u8, u8, u8),
+    pub is_teams: bool,
+    pub stage: u16,
+    /// Players in the game, by port. Empty ports are left out.
+    pub players: Vec<Player>,
+    pub random_seed: u32,
+    /// Whether the game is running the PAL version of Melee (1.5.0).
+    pub is_pal: Option<bool>,
+    /// Whether Pokémon Stadium transformations are disabled (2.0.0).
+    pub is_frozen_ps: Option<bool>,
+}
+
+/// Player details in Game Start, where the player block for port `i` is at
+/// `PLAYER_BLOCK_START + PLAYER_BLOCK_SIZE * i`.
+pub(crate) const PLAYER_BLOCK_START: usize = 0x65;
+pub(crate) const PLAYER_BLOCK_SIZE: usize = 0x24;
+pub(crate) const PLAYER_TYPE_EMPTY: u8 = 3;
+pub(crate) const DISPLAY_NAME_START: usize = 0x1A5;
+pub(crate) const DISPLAY_NAME_SIZE: usize = 0x1F;
+pub(crate) const CONNECT_CODE_START: usize = 0x221;
+pub(crate) const CONNECT_CODE_SIZE: usize = 0xA;
+
+impl GameStart {
+    pub fn parse(event: &[u8]) -> Result<GameStart, SlippiParseError> {
+        check_event(event, Event::GameStart, 0x141)?;
+
+        let is_teams = read_bool(event, 0xD).unwrap();
+        let mut players = Vec::new();
+
+        for port in 0..4 {
+            let block = PLAYER_BLOCK_START + PLAYER_BLOCK_SIZE * port;
+            let player_type =
+                match read_u8(event, block + 0x1).unwrap() {
+                    0 => PlayerType::Human,
+                    1 => PlayerType::Cpu,
+                    2 => PlayerType::Demo,
+                    PLAYER_TYPE_EMPTY => continue,
+                    other => {
+                        tracing::warn!("Unknown player type {} for port {}", other, port + 1);
+                        continue;
+                    }
+                };
+
+            let read_string = |start: usize, size: usize| {
+                event.get((start + size * port)..(start + size * (port + 1)))
+                    .map(decode_shift_jis)
+                    .filter(|s| !s.is_empty())
+            };
+
+            players.push(Player {
+                port: port as u8,
+                character: read_u8(event, block).unwrap(),
+                player_type,
+                stocks: read_u8(event, block + 0x2).unwrap(),
+                costume: read_u8(event, block + 0x3).unwrap(),
+                team: if is_teams { read_u8(event, block + 0x9) } else { None },
+                display_name: read_string(DISPLAY_NAME_START, DISPLAY_NAME_SIZE),
+                connect_code: read_string(CONNECT_CODE_START, CONNECT_CODE_SIZE),
+            });
+        }
+
+        Ok(GameStart {
+            version: (event[1], event[2], event[3]),
+            is_teams,
+            stage: read_u16(event, 0x13).unwrap(),
+            players,
+            random_seed: read_u32(event, 0x13D).unwrap(),
+            is_pal: read_bool(event, 0x1A1),
+            is_frozen_ps: read_bool(event, 0x1A2),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreFrameUpdate {
+    pub frame: i32,
+    /// Port index, where 0 is port 1.
+    pub port: u8,
+    /// Whether this is the follower of Ice Climbers, Nana.
+    pub is_follower: bool,
+    pub random_seed: u32,
+    pub action_state: u16,
+    pub x: f32,
+    pub y: f32,
+    pub facing_direction: f32,
+    pub joystick_x: f32,
+    pub joystick_y: f32,
+    pub c_stick_x: f32,
+    pub c_stick_y: f32,
+    pub trigger: f32,
+    pub buttons: u32,
+    pub physical_buttons: u16,
+    pub physical_l: f32,
+    pub physical_r: f32,
+    /// Percent at the start of the frame (1.4.0).
+    pub percent: Option<f32>,
+}
+
+impl PreFrameUpdate {
+    pub fn parse(event: &[u8]) -> Result<PreFrameUpdate, SlippiParseError> {
+        check_event(event, Event::FramePre, 0x3B)?;
+
+        Ok(PreFrameUpdate {
+            frame: read_i32(event, 0x1).unwrap(),
+            port: read_u8(event, 0x5).unwrap(),
+            is_follower: read_bool(event, 0x6).unwrap(),
+            random_seed: read_u32(event, 0x7).unwrap(),
+            action_state: read_u16(event, 0xB).unwrap(),
+            x: read_f32(event, 0xD).unwrap(),
+            y: read_f32(event, 0x11).unwrap(),
+            facing_direction: read_f32(event, 0x15).unwrap(),
+            joystick_x: read_f32(event, 0x19).unwrap(),
+            joystick_y: read_f32(event, 0x1D).unwrap(),
+            c_stick_x: read_f32(event, 0x21).unwrap(),
+            c_stick_y: read_f32(event, 0x25).unwrap(),
+            trigger: read_f32(event, 0x29).unwrap(),
+            buttons: read_u32(event, 0x2D).unwrap(),
+            physical_buttons: read_u16(event, 0x31).unwrap(),
+            physical_l: read_f32(event, 0x33).unwrap(),
+            physical_r: read_f32(event, 0x37).unwrap(),
+            percent: read_f32(event, 0x3C),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PostFrameUpdate {
+    pub frame: i32,
+    /// Port index, where 0 is port 1.
+    pub port: u8,
+    /// Whether this is the follower of Ice Climbers, Nana.
+    pub is_follower: bool,
+    /// Internal character ID, which unlike the external ID tells apart Zelda
+    /// and Sheik.
+    pub internal_character: u8,
+    pub action_state: u16,
+    pub x: f32,
+    pub y: f32,
+    pub facing_direction: f32,
+    pub percent: f32,
+    pub shield_size: f32,
+    pub last_attack_landed: u8,
+    pub combo_count: u8,
+    /// Port index of the player who last hit this player.
+    pub last_hit_by: u8,
+    pub stocks: u8,
+    /// Frames spent in the current action state (0.2.0).
+    pub action_state_frame: Option<f32>,
+    /// Whether the player is in the air (2.0.0).
+    pub is_airborne: Option<bool>,
+    /// Jumps remaining (2.0.0).
+    pub jumps_remaining: Option<u8>,
+    /// L-cancel status, where 0 is none, 1 is successful and 2 is
+    /// unsuccessful (2.0.0).
+    pub l_cancel_status: Option<u8>,
+}
+
+impl PostFrameUpdate {
+    pub fn parse(event: &[u8]) -> Result<PostFrameUpdate, SlippiParseError> {
+        check_event(event, Event::FramePost, 0x22)?;
+
+        Ok(PostFrameUpdate {
+            frame: read_i32(event, 0x1).unwrap(),
+            port: read_u8(event, 0x5).unwrap(),
+            is_follower: read_bool(event, 0x6).unwrap(),
+            internal_character: read_u8(event, 0x7).unwrap(),
+            action_state: read_u16(event, 0x8).unwrap(),
+            x: read_f32(event, 0xA).unwrap(),
+            y: read_f32(event, 0xE).unwrap(),
+            facing_direction: read_f32(event, 0x12).unwrap(),
+            percent: read_f32(event, 0x16).unwrap(),
+            shield_size: read_f32(event, 0x1A).unwrap(),
+            last_attack_landed: read_u8(event, 0x1E).unwrap(),
+            combo_count: read_u8(event, 0x1F).unwrap(),
+            last_hit_by: read_u8(event, 0x20).unwrap(),
+            stocks: read_u8(event, 0x21).unwrap(),
+            action_state_frame: read_f32(event, 0x22),
+            is_airborne: read_bool(event, 0x2F),
+            jumps_remaining: read_u8(event, 0x32),
+            l_cancel_status: read_u8(event, 0x33),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum GameEndMethod {
+    /// The game ended without a winner, before 2.0.0.
+    Unresolved,
+    /// The game ended with a winner, before 2.0.0.
+    Resolved,
+    Time,
+    Game,
+    NoContest,
+    Unknown(u8),
+}
+
+impl From<u8> for GameEndMethod {
+    fn from(value: u8) -> GameEndMethod {
+        match value {
+            0 => GameEndMethod::Unresolved,
+            1 => GameEndMethod::Time,
+            2 => GameEndMethod::Game,
+            3 => GameEndMethod::Resolved,
+            7 => GameEndMethod::NoContest,
+            other => GameEndMethod::Unknown(other)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GameEnd {
+    pub method: GameEndMethod,
+    /// Port index of the player who quit out with L+R+A+Start, if any
+    /// (2.0.0).
+    pub lras_initiator: Option<u8>,
+    /// Placement of each port, where 0 is first place, or -1 if the port was
+    /// empty (3.13.0).
+    pub placements: Option<[i8; 4]>,
+}
+
+impl GameEnd {
+    pub fn parse(event: &[u8]) -> Result<GameEnd, SlippiParseError> {
+        check_event(event, Event::GameEnd, 0x2)?;
+
+        let placements = event.get(0x3..0x7).map(|p| [p[0] as i8, p[1] as i8, p[2] as i8, p[3] as i8]);
+
+        Ok(GameEnd {
+            method: GameEndMethod::from(event[1]),
+            lras_initiator: read_i8(event, 0x2).and_then(|port| u8::try_from(port).ok()),
+            placements,
+        })
+    }
+}
+
+/// Character names by external character ID.
+const CHARACTER_NAMES: [&str; 26] = [
+    "CaptainFalcon", "DonkeyKong", "Fox", "GameAndWatch", "Kirby", "Bowser",
+    "Link", "Luigi", "Mario", "Marth", "Mewtwo", "Ness", "Peach", "Pikachu",
+    "IceClimbers", "Jigglypuff", "Samus", "Yoshi", "Zelda", "Sheik", "Falco",
+    "YoungLink", "DrMario", "Roy", "Pichu", "Ganondorf",
+];
+
+/// The name of a character by its external character ID, such as
+/// `CaptainFalcon`.
+pub fn character_name(character_id: u8) -> Option<&'static str> {
+    CHARACTER_NAMES.get(character_id as usize).copied()
+}
+
+/// The name of a stage by its ID, such as `FinalDestination`.
+pub fn stage_name(stage_id: u16) -> Option<&'static str> {
+    match stage_id {
+        2 => Some("FountainOfDreams"),
+        3 => Some("PokemonStadium"),
+        4 => Some("PeachsCastle"),
+        5 => Some("KongoJungle"),
+        6 => Some("Brinstar"),
+        7 => Some("Corneria"),
+        8 => Some("YoshisStory"),
+        9 => Some("Onett"),
+        10 => Some("MuteCity"),
+        11 => Some("RainbowCruise"),
+        12 => Some("JungleJapes"),
+        13 => Some("GreatBay"),
+        14 => Some("HyruleTemple"),
+        15 => Some("BrinstarDepths"),
+        16 => Some("YoshisIsland"),
+        17 => Some("GreenGreens"),
+        18 => Some("Fourside"),
+        19 => Some("MushroomKingdom"),
+        20 => Some("MushroomKingdom2"),
+        22 => Some("Venom"),
+        23 => Some("PokeFloats"),
+        24 => Some("BigBlue"),
+        25 => Some("IcicleMountain"),
+        26 => Some("Icetop"),
+        27 => Some("FlatZone"),
+        28 => Some("DreamLand"),
+        29 => Some("YoshisIslandN64"),
+        30 => Some("KongoJungleN64"),
+        31 => Some("Battlefield"),
+        32 => Some("FinalDestination"),
+        _ => None
+    }
+}
+
+/// A decoded Slippi event.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SlippiEvent {
+    Payloads(PayloadSizes),
+    GameStart(GameStart),
+    PreFrameUpdate(PreFrameUpdate),
+    PostFrameUpdate(PostFrameUpdate),
+    GameEnd(GameEnd),
+    /// Any other event, by its command byte. Its data is skipped over using
+    /// the size from Event Payloads.
+    Other(u8),
+}
+
+impl SlippiEvent {
+    /// Decode a complete event, including its command byte.
+    pub fn parse(event: &[u8]) -> Result<SlippiEvent, SlippiParseError> {
+        let command = event.first().copied().ok_or(SlippiParseError::TooShort { event: 0, expected: 1, actual: 0 })?;
+
+        match command {
+            c if c == Event::GameStart as u8 => GameStart::parse(event).map(SlippiEvent::GameStart),
+            c if c == Event::FramePre as u8 => PreFrameUpdate::parse(event).map(SlippiEvent::PreFrameUpdate),
+            c if c == Event::FramePost as u8 => PostFrameUpdate::parse(event).map(SlippiEvent::PostFrameUpdate),
+            c if c == Event::GameEnd as u8 => GameEnd::parse(event).map(SlippiEvent::GameEnd),
+            other => Ok(SlippiEvent::Other(other))
+        }
+    }
+}
+
+/// Splits raw Slippi data, which is not necessarily split on event
+/// boundaries, into complete events. [`EventParser`] and
+/// [`GameTracker`](crate::common::GameTracker) both build on it.
+#[derive(Debug, Default)]
+pub(crate) struct EventSplitter {
+    /// Data not yet split, because it does not contain a complete event.
+    buffer: Vec<u8>,
+    /// Position of the start of `buffer` in all data processed so far.
+    buffer_position: usize,
+    /// Payload sizes of the game in progress, if any.
+    payload_sizes: Option<PayloadSizes>,
+}
+
+impl EventSplitter {
+    /// Split the complete events in `data` and any data left over from
+    /// before. `on_event` is called with the position of each event in all
+    /// data processed so far, the event including its command byte, and the
+    /// payload sizes of its game. Data before the start of a game is skipped.
+    pub(crate) fn process(&mut self, data: &[u8], mut on_event: impl FnMut(usize, &[u8], &PayloadSizes)) {
+        self.buffer.extend_from_slice(data);
+        let mut pos = 0;
+
+        while pos < self.buffer.len() {
+            let remaining = &self.buffer[pos..];
+
+            match &self.payload_sizes {
+                None => {
+                    match parse_payloads(remaining) {
+                        Ok((bytes_read, payload_sizes)) => {
+                            on_event(self.buffer_position + pos, &remaining[..bytes_read], &payload_sizes);
+                            self.payload_sizes = Some(payload_sizes);
+                            pos += bytes_read;
+                        }
+                        Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                        // Not at the start of a game; keep looking.
+                        Err(_) => pos += 1
+                    }
+                }
+                Some(payload_sizes) => {
+                    let command = remaining[0];
+
+                    match payload_sizes.get(&command) {
+                        Some(&command_size) => {
+                            let event_size = command_size as usize + 1; // include command byte
+                            if remaining.len() < event_size {
+                                break;
+                            }
+
+                            on_event(self.buffer_position + pos, &remaining[..event_size], payload_sizes);
+
+                            if command == Event::GameEnd as u8 {
+                                self.payload_sizes = None;
+                            }
+
+                            pos += event_size;
+                        }
+                        None => {
+                            tracing::warn!("Unknown event {:#02x} in Slippi data, waiting for next game", command);
+                            self.payload_sizes = None;
+                        }
+                    }
+                }
+            }
+        }
+
+        self.buffer.drain(..pos);
+        self.buffer_position += pos;
+    }
+
+    /// Whether data has been left over, waiting for the rest of an event.
+    #[cfg(test)]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+}
+
+/// Decodes events from raw Slippi data, which does not need to be split on
+/// event boundaries.
+#[derive(Debug, Default)]
+pub struct EventParser {
+    splitter: EventSplitter,
+}
+
+impl EventParser {
+    pub fn new() -> EventParser {
+        EventParser::default()
+    }
+
+    /// Decode the complete events in `data` and any data left over from
+    /// before. Data before the start of a game is skipped.
+    pub fn process(&mut self, data: &[u8]) -> Vec<SlippiEvent> {
+        let mut events = Vec::new();
+
+        self.splitter.process(data, |_, event, payload_sizes| {
+            if event[0] == Event::Payloads as u8 {
+                events.push(SlippiEvent::Payloads(payload_sizes.clone()));
+                return;
+            }
+
+            match SlippiEvent::parse(event) {
+                Ok(event) => events.push(event),
+                Err(e) => {
+                    tracing::warn!("Error decoding Slippi event: {}", e);
+                    events.push(SlippiEvent::Other(event[0]));
+                }
+            }
+        });
+
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn game_start(players: &[(usize, u8, u8)]) -> Vec<u8> {
+        let mut event = vec![0u8; CONNECT_CODE_START + CONNECT_CODE_SIZE * 4];
+        event[0] = Event::GameStart as u8;
+        event[1..4].copy_from_slice(&[3, 16, 0]);
+        event[0x13..0x15].copy_from_slice(&31u16.to_be_bytes());
+
+        for port in 0..4 {
+            event[PLAYER_BLOCK_START + PLAYER_BLOCK_SIZE * port + 1] = PLAYER_TYPE_EMPTY;
+        }
+        for &(port, character, player_type) in players {
+            event[PLAYER_BLOCK_START + PLAYER_BLOCK_SIZE * port] = character;
+            event[PLAYER_BLOCK_START + PLAYER_BLOCK_SIZE * port + 1] = player_type;
+            event[PLAYER_BLOCK_START + PLAYER_BLOCK_SIZE * port + 2] = 4;
+        }
+
+        event
+    }
+
+    #[test]
+    fn game_start_parses_players_and_names() {
+        let mut event = game_start(&[(0, 2, 0), (3, 9, 1)]);
+        event[DISPLAY_NAME_START..][..4].copy_from_slice(b"Fizz");
+        event[CONNECT_CODE_START..][..7].copy_from_slice(b"FZ\x81\x94123");
+
+        let game_start = GameStart::parse(&event).unwrap();
+
+        assert_eq!(game_start.version, (3, 16, 0));
+        assert_eq!(game_start.stage, 31);
+        assert_eq!(game_start.players.len(), 2);
+        assert_eq!(game_start.players[0].display_name.as_deref(), Some("Fizz"));
+        assert_eq!(game_start.players[0].connect_code.as_deref(), Some("FZ#123"));
+        assert_eq!(game_start.players[1].port, 3);
+        assert_eq!(game_start.players[1].character, 9);
+        assert_eq!(game_start.players[1].player_type, PlayerType::Cpu);
+        assert_eq!(game_start.players[1].stocks, 4);
+
+        // Older versions have no names
+        let old_game_start = GameStart::parse(&event[..0x141]).unwrap();
+        assert_eq!(old_game_start.players[0].display_name, None);
+        assert_eq!(old_game_start.is_pal, None);
+
+        assert!(matches!(GameStart::parse(&event[..0x140]), Err(SlippiParseError::TooShort { .. })));
+    }
+
+    #[test]
+    fn event_parser_skips_unknown_events_by_payload_size() {
+        // Event Payloads declaring Game Start, Post-Frame Update, Game End
+        // and an event from a future Slippi version (0x50, 2 bytes).
+        let game_start = game_start(&[(0, 2, 0)]);
+        let mut payloads = vec![0x35, 13];
+        payloads.extend([0x36, 0, 0, 0x38, 0, 0x21, 0x39, 0, 6, 0x50, 0, 2]);
+        payloads[3..5].copy_from_slice(&((game_start.len() - 1) as u16).to_be_bytes());
+
+        let mut post_frame = vec![0u8; 0x22];
+        post_frame[0] = Event::FramePost as u8;
+        post_frame[1..5].copy_from_slice(&(-123i32).to_be_bytes());
+        post_frame[0x16..0x1A].copy_from_slice(&12.5f32.to_be_bytes());
+        post_frame[0x21] = 4;
+
+        let game_end = [0x39, 2, 0xFF, 0, 0xFF, 0xFF, 0xFF];
+
+        let data = [payloads, game_start, vec![0x50, 1, 2], post_frame, game_end.to_vec()].concat();
+        let mut parser = EventParser::new();
+
+        let mut events = parser.process(&data[..100]);
+        events.extend(parser.process(&data[100..]));
+
+        assert_eq!(events.len(), 5);
+        assert!(matches!(&events[0], SlippiEvent::Payloads(sizes) if sizes.get(&0x50) == Some(&2)));
+        assert!(matches!(&events[1], SlippiEvent::GameStart(game_start) if game_start.players.len() == 1));
+        assert_eq!(events[2], SlippiEvent::Other(0x50));
+        assert!(matches!(&events[3], SlippiEvent::PostFrameUpdate(post) if post.percent == 12.5 && post.stocks == 4 && post.is_airborne.is_none()));
+        assert_eq!(events[4], SlippiEvent::GameEnd(GameEnd {
+            method: GameEndMethod::Game,
+            lras_initiator: None,
+            placements: Some([0, -1, -1, -1]),
+        }));
+    }
+}
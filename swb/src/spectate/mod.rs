@@ -1,3 +1,20 @@
+use crate::overlay::OverlayFeed;
+
+use playback_delay::PlaybackControl;
+
 pub mod websocket_connection;
 pub mod playback_dolphin;
-pub mod slp_file_writer;
\ No newline at end of file
+pub mod playback_delay;
+pub mod slp_file_writer;
+pub(crate) mod slp_file;
+pub mod replay_name;
+
+/// Options for watching a stream.
+#[derive(Debug, Clone, Default)]
+pub struct SpectateOptions {
+    /// Publish the state of the game being watched to this feed.
+    pub overlay: Option<OverlayFeed>,
+    /// Watch behind live, as controlled by this. Otherwise, the stream is
+    /// played as soon as it is received.
+    pub playback: Option<PlaybackControl>,
+}
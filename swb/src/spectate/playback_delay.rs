@@ -0,0 +1,169 @@
+use std::{collections::VecDeque, pin::Pin, time::Duration};
+
+use async_stream::stream;
+use futures::StreamExt;
+use tokio::{
+    sync::watch,
+    time::{sleep_until, Instant}
+};
+
+use crate::common::SlippiDataStream;
+
+/// How much data may be held back while watching behind live. Past this, the
+/// oldest data is played early, which keeps memory bounded at the cost of
+/// getting closer to live.
+const MAX_BUFFERED_BYTES: usize = 256 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+struct PlaybackState {
+    /// How far behind live playback is.
+    lag: Duration,
+    /// When playback was paused, if it is.
+    paused_at: Option<Instant>,
+}
+
+/// Controls how far behind live a stream is watched. Clones control the same
+/// playback.
+#[derive(Debug, Clone)]
+pub struct PlaybackControl {
+    state: watch::Sender<PlaybackState>,
+}
+
+impl PlaybackControl {
+    /// Start watching `delay` behind live.
+    pub fn new(delay: Duration) -> PlaybackControl {
+        let (state, _) = watch::channel(PlaybackState { lag: delay, paused_at: None });
+        PlaybackControl { state }
+    }
+
+    /// Stop playback. Data keeps being received, and playback continues from
+    /// the same point when resumed.
+    pub fn pause(&self) {
+        self.state.send_modify(|state| {
+            state.paused_at.get_or_insert_with(Instant::now);
+        });
+    }
+
+    pub fn resume(&self) {
+        self.state.send_modify(|state| {
+            if let Some(paused_at) = state.paused_at.take() {
+                state.lag += paused_at.elapsed();
+            }
+        });
+    }
+
+    /// Play everything received so far, and continue without delay.
+    pub fn jump_to_live(&self) {
+        self.set_delay(Duration::ZERO);
+        self.resume();
+    }
+
+    /// Watch `delay` behind live from now on. Playback can only move forward,
+    /// so a longer delay takes effect as new data arrives.
+    pub fn set_delay(&self, delay: Duration) {
+        self.state.send_modify(|state| {
+            state.lag = delay;
+            state.paused_at = state.paused_at.map(|_| Instant::now());
+        });
+    }
+
+    /// How far behind live playback currently is.
+    pub fn lag(&self) -> Duration {
+        let state = *self.state.borrow();
+        state.lag + state.paused_at.map_or(Duration::ZERO, |paused_at| paused_at.elapsed())
+    }
+}
+
+enum PlaybackEvent {
+    Received(Option<Vec<u8>>),
+    Release,
+    ControlChanged,
+}
+
+/// Hold back the data of a stream according to `control`. Data still held back
+/// when the stream ends is played on schedule.
+pub(crate) fn delay_playback(stream: Pin<Box<SlippiDataStream>>, control: PlaybackControl) -> Pin<Box<SlippiDataStream>> {
+    let mut state = control.state.subscribe();
+
+    Box::pin(stream! {
+        let mut input = stream;
+        let mut input_ended = false;
+        let mut queue: VecDeque<(Instant, Vec<u8>)> = VecDeque::new();
+        let mut buffered_bytes = 0;
+
+        while !(input_ended && queue.is_empty()) {
+            let PlaybackState { lag, paused_at } = *state.borrow_and_update();
+            let next_release =
+                match (paused_at, queue.front()) {
+                    (None, Some((received_at, _))) => Some(*received_at + lag),
+                    _ => None
+                };
+
+            let event = tokio::select! {
+                received = input.next(), if !input_ended => PlaybackEvent::Received(received),
+                _ = sleep_until(next_release.unwrap_or_else(Instant::now)), if next_release.is_some() => PlaybackEvent::Release,
+                _ = state.changed() => PlaybackEvent::ControlChanged,
+            };
+
+            match event {
+                PlaybackEvent::Received(Some(data)) => {
+                    buffered_bytes += data.len();
+                    queue.push_back((Instant::now(), data));
+
+                    if buffered_bytes > MAX_BUFFERED_BYTES {
+                        tracing::warn!("Too much of the stream buffered, skipping ahead");
+                    }
+
+                    while buffered_bytes > MAX_BUFFERED_BYTES {
+                        let (_, data) = queue.pop_front().unwrap();
+                        buffered_bytes -= data.len();
+                        yield data;
+                    }
+                }
+                PlaybackEvent::Received(None) => input_ended = true,
+                PlaybackEvent::Release => {
+                    let (_, data) = queue.pop_front().unwrap();
+                    buffered_bytes -= data.len();
+                    yield data;
+                }
+                PlaybackEvent::ControlChanged => ()
+            }
+        }
+
+        // Keep the control alive for as long as playback, so that
+        // `state.changed()` never fails.
+        drop(control);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn delay_playback_follows_control() {
+        let control = PlaybackControl::new(Duration::from_secs(60));
+        let (sender, receiver) = futures::channel::mpsc::unbounded::<Vec<u8>>();
+        let mut playback = delay_playback(Box::pin(receiver), control.clone());
+
+        sender.unbounded_send(vec![1]).unwrap();
+        sender.unbounded_send(vec![2]).unwrap();
+        let next = tokio::time::timeout(Duration::from_millis(50), playback.next()).await;
+        assert!(next.is_err(), "data was played before its delay was up");
+
+        control.jump_to_live();
+        assert_eq!(playback.next().await, Some(vec![1]));
+        assert_eq!(playback.next().await, Some(vec![2]));
+
+        control.pause();
+        sender.unbounded_send(vec![3]).unwrap();
+        let next = tokio::time::timeout(Duration::from_millis(50), playback.next()).await;
+        assert!(next.is_err(), "data was played while paused");
+        assert!(control.lag() >= Duration::from_millis(50));
+
+        control.resume();
+        sender.close_channel();
+        assert_eq!(playback.next().await, Some(vec![3]));
+        assert_eq!(playback.next().await, None);
+    }
+}
@@ -15,33 +15,43 @@ struct CommSpec {
     replay: Option<String>
 }
 
-pub(crate) fn launch_playback_dolphin() -> Result<Child, ConfigError> {
-    let spec = CommSpec { mode: "mirror".to_string(), commandId: "0".to_string(), replay: None };
-    write_comm_spec(spec);
-
-    let config = config::get_application_config();
-
-    let child = Command::new(config.playback_dolphin_path()?)
-        .args(["-e", &config.iso_path().unwrap(), "-i", config.comm_spec_path().to_str().unwrap()])
-        .spawn()
-        .expect("failed to execute command");
-
-    Ok(child)
-}
-
-pub(crate) fn mirror_file(fp: PathBuf) {
-    let command_id: String = rand::rng()
-        .sample_iter(&Alphanumeric)
-        .take(16)
-        .map(char::from)
-        .collect();
-
-    let spec = CommSpec { mode: "mirror".to_string(), commandId: command_id.to_string(), replay: Some(fp.to_str().unwrap().to_string()) };
-    write_comm_spec(spec);
+/// A Playback Dolphin instance, which is controlled through its own comm spec
+/// file so that several can run at once.
+pub(crate) struct PlaybackDolphin {
+    comm_spec_path: PathBuf,
 }
 
-fn write_comm_spec(spec: CommSpec) {
-    let config = config::get_application_config();
-    let file = File::create(config.comm_spec_path()).unwrap();
-    serde_json::to_writer(file, &spec).unwrap();
+impl PlaybackDolphin {
+    /// Launch Playback Dolphin. `instance` names its comm spec file, and must
+    /// be unique among the instances running at once.
+    pub(crate) fn launch(instance: Option<&str>) -> Result<(PlaybackDolphin, Child), ConfigError> {
+        let config = config::get_application_config();
+        let playback_dolphin = PlaybackDolphin { comm_spec_path: config.comm_spec_path(instance) };
+
+        let spec = CommSpec { mode: "mirror".to_string(), commandId: "0".to_string(), replay: None };
+        playback_dolphin.write_comm_spec(spec);
+
+        let child = Command::new(config.playback_dolphin_path()?)
+            .args(["-e", &config.iso_path().unwrap(), "-i", playback_dolphin.comm_spec_path.to_str().unwrap()])
+            .spawn()
+            .expect("failed to execute command");
+
+        Ok((playback_dolphin, child))
+    }
+
+    pub(crate) fn mirror_file(&self, fp: PathBuf) {
+        let command_id: String = rand::rng()
+            .sample_iter(&Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect();
+
+        let spec = CommSpec { mode: "mirror".to_string(), commandId: command_id.to_string(), replay: Some(fp.to_str().unwrap().to_string()) };
+        self.write_comm_spec(spec);
+    }
+
+    fn write_comm_spec(&self, spec: CommSpec) {
+        let file = File::create(&self.comm_spec_path).unwrap();
+        serde_json::to_writer(file, &spec).unwrap();
+    }
 }
@@ -0,0 +1,152 @@
+//! Naming of replay files from a template, set with `replay_name_template` in
+//! swb's settings.json. The template is relative to the directory replays are
+//! saved in, and may contain `/` to save replays in subdirectories. The
+//! following placeholders are available:
+//!
+//! - `{timestamp}`: the time the game started, such as `20250607183012`
+//! - `{date}`: the date the game started, such as `2025-06-07`
+//! - `{time}`: the time of day the game started, such as `183012`
+//! - `{stream}`: the stream ID, or `local` if the stream has none
+//! - `{stage}`: the stage name, such as `Battlefield`
+//! - `{characters}`: each player's character, such as `Fox-Marth`
+//! - `{players}`: each player's connect code, or display name or port if
+//!   unavailable, such as `ABC#123-P2`
+//!
+//! For example, `{date}/{players}_{stage}` saves replays in a directory per
+//! day. If a file with the resulting name already exists, a number is added
+//! to the end of the name.
+
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local};
+
+use crate::slippi::{self, GameStart};
+
+pub(crate) const DEFAULT_REPLAY_NAME_TEMPLATE: &str = "Game_{timestamp}";
+
+/// Fill in a placeholder from the Game Start event and stream details.
+/// Returns `None` for unknown placeholders.
+fn placeholder_value(placeholder: &str, game_start: Option<&GameStart>, stream_id: Option<u32>, start_time: &DateTime<Local>) -> Option<String> {
+    let players = || game_start.map_or(&[][..], |game_start| &game_start.players).iter();
+
+    let value =
+        match placeholder {
+            "timestamp" => start_time.format("%Y%m%d%H%M%S").to_string(),
+            "date" => start_time.format("%Y-%m-%d").to_string(),
+            "time" => start_time.format("%H%M%S").to_string(),
+            "stream" => stream_id.map_or("local".to_string(), |stream_id| stream_id.to_string()),
+            "stage" => {
+                match game_start {
+                    Some(game_start) => slippi::stage_name(game_start.stage).map_or(format!("Stage{}", game_start.stage), str::to_string),
+                    None => "UnknownStage".to_string()
+                }
+            }
+            "characters" => {
+                players().map(|player| {
+                    slippi::character_name(player.character).map_or(format!("Character{}", player.character), |name| name.to_string())
+                }).collect::<Vec<String>>().join("-")
+            }
+            "players" => {
+                players().map(|player| {
+                    player.connect_code.clone()
+                        .or_else(|| player.display_name.clone())
+                        .unwrap_or(format!("P{}", player.port + 1))
+                }).collect::<Vec<String>>().join("-")
+            }
+            _ => return None
+        };
+
+    Some(value)
+}
+
+/// Replace characters which are not allowed in file names.
+fn sanitize(value: &str) -> String {
+    value.chars()
+        .map(|c| if c.is_control() || r#"<>:"/\|?*"#.contains(c) { '_' } else { c })
+        .collect()
+}
+
+/// Get the path to save a new replay at, according to `template`. The path
+/// returned does not exist yet, but its parent directory may not either.
+pub(crate) fn replay_path(directory: &Path, template: &str, game_start: &[u8], stream_id: Option<u32>, start_time: &DateTime<Local>) -> PathBuf {
+    let game_start = GameStart::parse(game_start).ok();
+    let game_start = game_start.as_ref();
+    let mut name = String::new();
+    let mut rest = template;
+
+    while let Some(open) = rest.find('{') {
+        name.push_str(&rest[..open]);
+        rest = &rest[open..];
+
+        let value = rest.find('}').and_then(|close| {
+            placeholder_value(&rest[1..close], game_start, stream_id, start_time).map(|value| (close, value))
+        });
+
+        match value {
+            Some((close, value)) => {
+                name.push_str(&sanitize(&value));
+                rest = &rest[(close + 1)..];
+            }
+            None => {
+                name.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    name.push_str(rest);
+
+    // Only allow subdirectories inside of `directory`
+    let mut path = directory.to_path_buf();
+    for component in name.split(['/', '\\']).filter(|c| !c.is_empty() && *c != "." && *c != "..") {
+        path.push(sanitize(component));
+    }
+    if path == directory {
+        path.push(sanitize(&placeholder_value("timestamp", game_start, stream_id, start_time).unwrap()));
+    }
+
+    let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
+    let mut candidate = path.with_file_name(format!("{}.slp", file_name));
+    let mut n = 2;
+
+    while candidate.exists() {
+        candidate = path.with_file_name(format!("{}_{}.slp", file_name, n));
+        n += 1;
+    }
+
+    candidate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use crate::slippi::{Event, CONNECT_CODE_SIZE, CONNECT_CODE_START, PLAYER_BLOCK_SIZE, PLAYER_BLOCK_START, PLAYER_TYPE_EMPTY};
+
+    #[test]
+    fn replay_path_fills_template_and_avoids_collisions() {
+        let directory = std::env::temp_dir().join(format!("swb-replay-name-test-{}", std::process::id()));
+        let start_time = Local.with_ymd_and_hms(2025, 6, 7, 18, 30, 12).unwrap();
+
+        let mut game_start = vec![0u8; CONNECT_CODE_START + CONNECT_CODE_SIZE * 4];
+        game_start[0] = Event::GameStart as u8;
+        game_start[0x14] = 31; // stage
+        for port in 0..4 {
+            game_start[PLAYER_BLOCK_START + PLAYER_BLOCK_SIZE * port] = [2, 9, 0, 0][port];
+            game_start[PLAYER_BLOCK_START + PLAYER_BLOCK_SIZE * port + 1] = if port < 2 { 0 } else { PLAYER_TYPE_EMPTY };
+        }
+        game_start[CONNECT_CODE_START..][..7].copy_from_slice(b"AB\x81\x94123");
+
+        let template = "{stream}/{date}/{players}_{characters}_{stage}_{unknown}";
+        let path = replay_path(&directory, template, &game_start, Some(12), &start_time);
+        assert_eq!(path, directory.join("12").join("2025-06-07").join("AB#123-P2_Fox-Marth_Battlefield_{unknown}.slp"));
+
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, []).unwrap();
+        let next_path = replay_path(&directory, template, &game_start, Some(12), &start_time);
+        assert_eq!(next_path.file_name().unwrap(), "AB#123-P2_Fox-Marth_Battlefield_{unknown}_2.slp");
+
+        assert_eq!(replay_path(&directory, "../{time}", &game_start, None, &start_time), directory.join("183012.slp"));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,267 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{Seek, SeekFrom, Write},
+    path::Path
+};
+
+use byteorder::{BE, ByteOrder};
+use chrono::Utc;
+
+use crate::slippi::{Event, GameStart};
+
+/// The beginning of a .slp file, up to the length of the raw element.
+/// `{U\x03raw[$U#l`, which opens the UBJSON object and declares the `raw`
+/// key as an array of bytes with a 4-byte length.
+pub(crate) const RAW_HEADER: &[u8] = b"{U\x03raw[$U#l";
+
+/// The frame before the first frame of a game.
+const NO_FRAME: i32 = -124;
+
+#[derive(Debug, Default)]
+struct PlayerMetadata {
+    /// Frames played by each internal character ID.
+    characters: BTreeMap<u8, u32>,
+    netplay_name: Option<String>,
+    connect_code: Option<String>,
+}
+
+#[derive(Debug)]
+struct Metadata {
+    start_at: String,
+    last_frame: i32,
+    players: BTreeMap<u8, PlayerMetadata>,
+}
+
+impl Metadata {
+    fn read_event(&mut self, event: &[u8]) {
+        let command = event[0];
+
+        if command == Event::GameStart as u8 {
+            self.read_game_start(event);
+        } else if [Event::FrameStart as u8, Event::FramePre as u8, Event::FramePost as u8, Event::FrameEnd as u8].contains(&command) && event.len() >= 5 {
+            self.last_frame = self.last_frame.max(BE::read_i32(&event[1..5]));
+
+            // Post-Frame Update holds the character actually in play, which
+            // for example tells Zelda and Sheik apart.
+            if command == Event::FramePost as u8 && event.len() >= 8 && event[6] == 0
+                && let Some(player) = self.players.get_mut(&event[5]) {
+                *player.characters.entry(event[7]).or_default() += 1;
+            }
+        }
+    }
+
+    fn read_game_start(&mut self, event: &[u8]) {
+        let game_start =
+            match GameStart::parse(event) {
+                Ok(game_start) => game_start,
+                Err(e) => {
+                    tracing::warn!("Error reading players for replay metadata: {}", e);
+                    return;
+                }
+            };
+
+        for player in game_start.players {
+            self.players.insert(player.port, PlayerMetadata {
+                characters: BTreeMap::new(),
+                netplay_name: player.display_name,
+                connect_code: player.connect_code,
+            });
+        }
+    }
+
+    /// Encode the metadata as a UBJSON object, as expected by Slippi tools.
+    fn to_ubjson(&self) -> Vec<u8> {
+        let mut out = vec![b'{'];
+
+        write_ubjson_key(&mut out, "startAt");
+        write_ubjson_string(&mut out, &self.start_at);
+        write_ubjson_key(&mut out, "lastFrame");
+        write_ubjson_int(&mut out, self.last_frame);
+
+        write_ubjson_key(&mut out, "players");
+        out.push(b'{');
+        for (port, player) in &self.players {
+            write_ubjson_key(&mut out, &port.to_string());
+            out.push(b'{');
+
+            write_ubjson_key(&mut out, "characters");
+            out.push(b'{');
+            for (character, frames) in &player.characters {
+                write_ubjson_key(&mut out, &character.to_string());
+                write_ubjson_int(&mut out, *frames as i32);
+            }
+            out.push(b'}');
+
+            if player.netplay_name.is_some() || player.connect_code.is_some() {
+                write_ubjson_key(&mut out, "names");
+                out.push(b'{');
+                if let Some(netplay_name) = &player.netplay_name {
+                    write_ubjson_key(&mut out, "netplay");
+                    write_ubjson_string(&mut out, netplay_name);
+                }
+                if let Some(connect_code) = &player.connect_code {
+                    write_ubjson_key(&mut out, "code");
+                    write_ubjson_string(&mut out, connect_code);
+                }
+                out.push(b'}');
+            }
+
+            out.push(b'}');
+        }
+        out.push(b'}');
+
+        write_ubjson_key(&mut out, "playedOn");
+        write_ubjson_string(&mut out, "network");
+
+        out.push(b'}');
+        out
+    }
+}
+
+fn write_ubjson_key(out: &mut Vec<u8>, key: &str) {
+    write_ubjson_length(out, key.len());
+    out.extend_from_slice(key.as_bytes());
+}
+
+fn write_ubjson_string(out: &mut Vec<u8>, value: &str) {
+    out.push(b'S');
+    write_ubjson_key(out, value);
+}
+
+fn write_ubjson_int(out: &mut Vec<u8>, value: i32) {
+    out.push(b'l');
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn write_ubjson_length(out: &mut Vec<u8>, length: usize) {
+    match u8::try_from(length) {
+        Ok(length) => out.extend_from_slice(&[b'U', length]),
+        Err(_) => write_ubjson_int(out, length as i32)
+    }
+}
+
+/// A .slp replay file being written. The raw length and metadata are filled
+/// in when the file is finished, or dropped if the game was interrupted, so
+/// that the file is readable either way.
+pub(crate) struct SlpFile {
+    file: File,
+    raw_length: u32,
+    metadata: Metadata,
+    finished: bool,
+}
+
+impl SlpFile {
+    pub(crate) fn create(path: &Path) -> std::io::Result<SlpFile> {
+        let mut file = File::create(path)?;
+
+        // The raw length is left as 0 until the game ends, which tells
+        // readers the file is still being written.
+        file.write_all(RAW_HEADER)?;
+        file.write_all(&[0; 4])?;
+
+        Ok(SlpFile {
+            file,
+            raw_length: 0,
+            metadata: Metadata {
+                start_at: Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+                last_frame: NO_FRAME,
+                players: BTreeMap::new(),
+            },
+            finished: false,
+        })
+    }
+
+    /// Write a complete Slippi event.
+    pub(crate) fn write_event(&mut self, event: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(event)?;
+        self.raw_length += event.len() as u32;
+        self.metadata.read_event(event);
+        Ok(())
+    }
+
+    /// Write the metadata and raw length, completing the file.
+    pub(crate) fn finish(mut self) -> std::io::Result<()> {
+        self.finished = true;
+        self.write_footer()
+    }
+
+    fn write_footer(&mut self) -> std::io::Result<()> {
+        self.file.write_all(b"U\x08metadata")?;
+        self.file.write_all(&self.metadata.to_ubjson())?;
+        self.file.write_all(b"}")?;
+
+        self.file.seek(SeekFrom::Start(RAW_HEADER.len() as u64))?;
+        self.file.write_all(&self.raw_length.to_be_bytes())?;
+        self.file.flush()
+    }
+}
+
+impl Drop for SlpFile {
+    fn drop(&mut self) {
+        if !self.finished && let Err(e) = self.write_footer() {
+            tracing::warn!("Error finishing interrupted replay file: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::slippi::{
+        CONNECT_CODE_SIZE,
+        CONNECT_CODE_START,
+        DISPLAY_NAME_SIZE,
+        DISPLAY_NAME_START,
+        PLAYER_BLOCK_SIZE,
+        PLAYER_BLOCK_START,
+        PLAYER_TYPE_EMPTY
+    };
+
+    #[test]
+    fn slp_file_wraps_events_with_header_and_metadata() {
+        let path = std::env::temp_dir().join(format!("swb-slp-file-test-{}.slp", std::process::id()));
+
+        let mut game_start = vec![0u8; CONNECT_CODE_START + CONNECT_CODE_SIZE * 4];
+        game_start[0] = Event::GameStart as u8;
+        for port in 0..4 {
+            game_start[PLAYER_BLOCK_START + PLAYER_BLOCK_SIZE * port + 1] = if port == 1 { 0 } else { PLAYER_TYPE_EMPTY };
+        }
+        game_start[(DISPLAY_NAME_START + DISPLAY_NAME_SIZE)..][..4].copy_from_slice(b"Fizz");
+        game_start[(CONNECT_CODE_START + CONNECT_CODE_SIZE)..][..7].copy_from_slice(b"FZ\x81\x94123");
+
+        let mut post_frame = vec![Event::FramePost as u8];
+        post_frame.extend_from_slice(&(-123i32).to_be_bytes());
+        post_frame.extend_from_slice(&[1, 0, 0x13]);
+
+        let mut slp_file = SlpFile::create(&path).unwrap();
+        slp_file.write_event(&game_start).unwrap();
+        slp_file.write_event(&post_frame).unwrap();
+        slp_file.finish().unwrap();
+
+        let contents = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let raw_length = game_start.len() + post_frame.len();
+        let raw_start = RAW_HEADER.len() + 4;
+        assert!(contents.starts_with(RAW_HEADER));
+        assert_eq!(BE::read_u32(&contents[RAW_HEADER.len()..raw_start]) as usize, raw_length);
+        assert_eq!(&contents[raw_start..(raw_start + raw_length)], [game_start, post_frame].concat());
+
+        // The start time is followed by the rest of the metadata, which is
+        // deterministic.
+        let metadata = &contents[(raw_start + raw_length)..];
+        let start_at_prefix = b"U\x08metadata{U\x07startAtSU\x14";
+        assert!(metadata.starts_with(start_at_prefix));
+        assert_eq!(
+            &metadata[(start_at_prefix.len() + 0x14)..],
+            [
+                b"U\x09lastFramel\xFF\xFF\xFF\x85".as_slice(),
+                b"U\x07players{U\x011{",
+                b"U\x0Acharacters{U\x0219l\0\0\0\x01}",
+                b"U\x05names{U\x07netplaySU\x04FizzU\x04codeSU\x06FZ#123}",
+                b"}}U\x08playedOnSU\x07network}}"
+            ].concat()
+        );
+    }
+}
@@ -1,45 +1,117 @@
 use std::{
-    collections::HashMap,
-    fs::File,
-    io::{BufReader, Error, ErrorKind, Read, Write},
-    path::PathBuf
+    io::{Error, ErrorKind, Read, Write},
+    path::PathBuf,
+    sync::Arc
 };
 
 use async_process::Child;
-use byteorder::{BE, ReadBytesExt};
-use chrono::{DateTime, Local};
+use byteorder::ReadBytesExt;
+use chrono::Local;
 
-use crate::{config::{self, ConfigError}, spectate::playback_dolphin};
+use crate::{
+    config::{self, ConfigError},
+    slippi::{PayloadSizes, SlippiEvent},
+    spectate::{
+        playback_dolphin::PlaybackDolphin,
+        replay_name,
+        slp_file::SlpFile
+    },
+    stats::{GameSummary, StatsTracker}
+};
 
-type PayloadSizes = HashMap<u8, u16>;
+// These used to be defined here
+pub use crate::slippi::{Event, parse_payloads};
 
 // TODO: New name since this is really a full dolphin mirror manager
 pub struct SlpFileWriter {
-    mirror_in_dolphin: bool,
+    /// Where games are mirrored as they start. May be shared with other
+    /// writers, in which case it shows whichever started a game last.
+    playback_dolphin: Option<Arc<PlaybackDolphin>>,
     spectate_directory_path: PathBuf,
-    current_file: Option<File>,
+    replay_name_template: String,
+    /// The ID of the stream being written, for naming replays.
+    stream_id: Option<u32>,
+    current_file: Option<SlpFile>,
+    /// Where the current file is saved, to save its stats next to.
+    current_path: Option<PathBuf>,
+    stats: StatsTracker,
+    /// Event Payloads of a game whose file will be created at Game Start.
+    pending_payloads: Option<Vec<u8>>,
     payload_sizes: Option<PayloadSizes>,
+    /// Data not yet written, because it does not contain a complete event.
+    buffer: Vec<u8>,
 }
 
 impl SlpFileWriter {
-    pub fn new(mirror_in_dolphin: bool) -> Result<(SlpFileWriter, Option<Child>), ConfigError> {
-        let dolphin_process =
+    pub fn new(mirror_in_dolphin: bool, stream_id: Option<u32>) -> Result<(SlpFileWriter, Option<Child>), ConfigError> {
+        SlpFileWriter::new_instance(mirror_in_dolphin, stream_id, None)
+    }
+
+    /// Like [`SlpFileWriter::new`], for one of several streams watched at
+    /// once. `instance` must be unique among them; it tells apart each
+    /// stream's Playback Dolphin, and names the subdirectory of the spectate
+    /// directory its replays are saved in.
+    pub fn new_instance(mirror_in_dolphin: bool, stream_id: Option<u32>, instance: Option<&str>) -> Result<(SlpFileWriter, Option<Child>), ConfigError> {
+        let (playback_dolphin, dolphin_process) =
             if mirror_in_dolphin {
-                Some(playback_dolphin::launch_playback_dolphin()?)
+                let (playback_dolphin, child) = PlaybackDolphin::launch(instance)?;
+                (Some(Arc::new(playback_dolphin)), Some(child))
             } else {
-                None
+                (None, None)
             };
 
+        Ok((SlpFileWriter::spectating(playback_dolphin, stream_id, instance)?, dolphin_process))
+    }
+
+    /// Create a writer which mirrors its games in a Playback Dolphin shared
+    /// with other writers. `instance` names the subdirectory of the spectate
+    /// directory replays are saved in.
+    pub(crate) fn with_playback_dolphin(playback_dolphin: Arc<PlaybackDolphin>, stream_id: Option<u32>, instance: Option<&str>) -> Result<SlpFileWriter, ConfigError> {
+        SlpFileWriter::spectating(Some(playback_dolphin), stream_id, instance)
+    }
+
+    fn spectating(playback_dolphin: Option<Arc<PlaybackDolphin>>, stream_id: Option<u32>, instance: Option<&str>) -> Result<SlpFileWriter, ConfigError> {
         let config = config::get_application_config();
+        let spectate_directory_path =
+            match instance {
+                Some(instance) => config.get_spectate_replay_directory_path()?.join(instance),
+                None => config.get_spectate_replay_directory_path()?
+            };
+
+        Ok(SlpFileWriter {
+            playback_dolphin,
+            spectate_directory_path,
+            replay_name_template: config.get_replay_name_template()?,
+            stream_id,
+            current_file: None,
+            current_path: None,
+            stats: StatsTracker::new(),
+            pending_payloads: None,
+            payload_sizes: None,
+            buffer: Vec::new(),
+        })
+    }
 
-        Ok((SlpFileWriter {
-            mirror_in_dolphin: mirror_in_dolphin,
-            spectate_directory_path: config.get_spectate_replay_directory_path()?,
+    /// Create a writer which only saves games to `directory`, without
+    /// mirroring them, naming them according to `replay_name_template`. This
+    /// does not depend on Slippi Launcher or swb's settings being set up.
+    pub fn with_directory(directory: PathBuf, stream_id: Option<u32>, replay_name_template: &str) -> SlpFileWriter {
+        SlpFileWriter {
+            playback_dolphin: None,
+            spectate_directory_path: directory,
+            replay_name_template: replay_name_template.to_string(),
+            stream_id,
             current_file: None,
+            current_path: None,
+            stats: StatsTracker::new(),
+            pending_payloads: None,
             payload_sizes: None,
-        }, dolphin_process))
+            buffer: Vec::new(),
+        }
     }
 
+    /// Read the next event from `data`, returning its size. Fails with
+    /// [`ErrorKind::UnexpectedEof`] if `data` does not contain the whole event.
     pub fn read_next_event<R: Read>(&mut self, mut data: R) -> std::io::Result<usize> {
         // so payload sizes might be send
         match &self.payload_sizes {
@@ -50,9 +122,11 @@ impl SlpFileWriter {
             }
             Some(payload_sizes) => {
                 let command = data.read_u8()?;
-                let command_size = payload_sizes.get(&command).unwrap().to_owned();
+                let command_size = payload_sizes.get(&command).ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidData, format!("unknown event {:#02x}", command))
+                })?.to_owned();
                 let mut read_buf = vec![0 as u8; command_size as usize];
-                data.read_exact(&mut read_buf).unwrap();
+                data.read_exact(&mut read_buf)?;
                 Ok((command_size + 1) as usize) // include command byte in read size
             }
         }
@@ -60,51 +134,129 @@ impl SlpFileWriter {
 
     pub fn write_payload(&mut self, data: &[u8]) -> std::io::Result<usize> {
         if let Some(ref mut file) = self.current_file {
-            file.write_all(data)?;
+            file.write_event(data)?;
             Ok(data.len())
         } else {
             Ok(0)
         }
     }
+
+    /// Log the summary of a game, and save it as JSON next to its replay.
+    fn write_summary(&self, summary: &GameSummary) -> std::io::Result<()> {
+        tracing::info!("Game summary: {}", summary);
+
+        if let Some(path) = &self.current_path {
+            let json = serde_json::to_vec_pretty(summary)?;
+            std::fs::write(path.with_extension("json"), json)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_event(&mut self, event_data: &[u8]) -> std::io::Result<()> {
+        let summary =
+            match SlippiEvent::parse(event_data) {
+                Ok(event) => self.stats.read_event(&event),
+                Err(e) => {
+                    tracing::debug!("Error decoding Slippi event for stats: {}", e);
+                    None
+                }
+            };
+
+        // Set current file and mirror as needed according to event type
+        match event_data[0] {
+            0x35 => {
+                // event payloads; new game started. The file is created at
+                // Game Start, which has the details for naming it.
+                self.current_file = None;
+                self.current_path = None;
+                self.pending_payloads = Some(event_data.to_vec());
+            }
+            0x36 => {
+                // game start
+                if let Some(payloads) = self.pending_payloads.take() {
+                    let fp = replay_name::replay_path(
+                        &self.spectate_directory_path,
+                        &self.replay_name_template,
+                        event_data,
+                        self.stream_id,
+                        &Local::now()
+                    );
+                    if let Some(parent) = fp.parent() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+                    self.current_file = Some(SlpFile::create(&fp)?);
+                    self.current_path = Some(fp.clone());
+
+                    if let Some(playback_dolphin) = &self.playback_dolphin {
+                        playback_dolphin.mirror_file(fp);
+                    }
+
+                    self.write_payload(&payloads)?;
+                }
+
+                self.write_payload(event_data)?;
+            }
+            0x39 => {
+                // game end
+                self.write_payload(event_data)?;
+                self.payload_sizes = None;
+                if let Some(file) = self.current_file.take() {
+                    file.finish()?;
+                }
+                // The replay itself is complete, so failing to save its
+                // summary should not interrupt the stream.
+                if let Some(summary) = summary
+                    && let Err(e) = self.write_summary(&summary) {
+                    tracing::warn!("Unable to save game summary: {}", e);
+                }
+                self.current_path = None;
+            }
+            _ => {
+                self.write_payload(event_data)?;
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl Write for SlpFileWriter {
+    /// Write Slippi data, which does not need to be split on event boundaries.
+    /// Incomplete events are held until the rest of their data is written.
     fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
-        let mut r = BufReader::new(data);
-        let mut bytes_written: usize = 0;
-
-        while let Ok(bytes_read) = self.read_next_event(&mut r) {
-            let event_data = &data[bytes_written..(bytes_written + bytes_read)];
-
-            // Set current file and mirror as needed according to event type
-            match event_data[0] {
-                0x35 => {
-                    // event payloads; new game started
-                    let current_local: DateTime<Local> = Local::now();
-                    let dt = current_local.format("%Y%m%d%H%M%S");
-                    let filename = format!("Game_{}.slp", dt);
-                    let fp = self.spectate_directory_path.join(filename.clone());
-                    self.current_file = Some(File::create(&fp).unwrap());
-
-                    if self.mirror_in_dolphin {
-                        playback_dolphin::mirror_file(fp);
-                    }
+        let mut buffer = std::mem::take(&mut self.buffer);
+        buffer.extend_from_slice(data);
+        let mut pos = 0;
 
-                    bytes_written += self.write_payload(event_data)?;
-                }
-                0x39 => {
-                    // game end
-                    bytes_written += self.write_payload(event_data)?;
-                    self.current_file = None;
-                    self.payload_sizes = None;
+        while pos < buffer.len() {
+            match self.read_next_event(&buffer[pos..]) {
+                Ok(bytes_read) => {
+                    if let Err(e) = self.write_event(&buffer[pos..(pos + bytes_read)]) {
+                        self.buffer = buffer.split_off(pos + bytes_read);
+                        return Err(e);
+                    }
+                    pos += bytes_read;
                 }
-                _ => {
-                    bytes_written += self.write_payload(event_data)?;
+                // Wait for the rest of the event
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => {
+                    if self.payload_sizes.is_some() {
+                        tracing::warn!("Error reading Slippi data, waiting for next game: {}", e);
+                        self.current_file = None;
+                        self.current_path = None;
+                        self.pending_payloads = None;
+                        self.payload_sizes = None;
+                    } else {
+                        // Not at the start of a game; keep looking.
+                        pos += 1;
+                    }
                 }
             }
         }
 
-        Ok(bytes_written)
+        self.buffer = buffer.split_off(pos);
+        Ok(data.len())
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
@@ -112,62 +264,40 @@ impl Write for SlpFileWriter {
     }
 }
 
-#[repr(u8)]
-pub enum Event {
-    MessageSplitter = 0x10,
-    Payloads = 0x35,
-    GameStart = 0x36,
-    FramePre = 0x37,
-    FramePost = 0x38,
-    GameEnd = 0x39,
-    FrameStart = 0x3A,
-    Item = 0x3B,
-    FrameEnd = 0x3C,
-    GeckoCodes = 0x3D,
-    FodPlatform = 0x3F,
-    DreamlandWhispy = 0x40,
-    StadiumTransformation = 0x41,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spectate::{replay_name::DEFAULT_REPLAY_NAME_TEMPLATE, slp_file::RAW_HEADER};
 
-// https://github.com/hohav/peppi/blob/aae5bd380fb6660d846797b19dd66dd232b5b04c/src/io/slippi/de.rs#L531
-
-/// Parses an Event Payloads event from `r`, which must come first in the raw
-/// stream and tells us the sizes for all other events to follow.
-///
-/// Returns the number of bytes read, and a map of event codes to payload sizes.
-/// This map uses raw event codes as keys (as opposed to `Event` enum values)
-/// for forwards compatibility, to allow skipping unknown events.
-pub fn parse_payloads<R: Read>(mut r: R) -> std::io::Result<(usize, PayloadSizes)> {
-    let code = r.read_u8()?;
-    if code != Event::Payloads as u8 {
-        return Err(Error::new(ErrorKind::Other, format!("expected event payloads, but got: {:#02x}", code)));
-    }
+    #[test]
+    fn write_reassembles_events_split_across_writes() {
+        let spectate_directory_path = std::env::temp_dir().join(format!("swb-slp-file-writer-test-{}", std::process::id()));
+        std::fs::create_dir_all(&spectate_directory_path).unwrap();
 
-    // Size in bytes of the subsequent list of payload-size kv pairs.
-    // Each pair is 3 bytes, so this size should be divisible by 3.
-    // However the value includes this size byte itself, so it's off-by-one.
-    let size = r.read_u8()?;
-    if size % 3 != 1 {
-        return Err(Error::new(ErrorKind::Other, format!("invalid payload size: {}", size)));
-    }
+        let mut writer = SlpFileWriter::with_directory(spectate_directory_path.clone(), None, DEFAULT_REPLAY_NAME_TEMPLATE);
 
-    let mut buf = vec![0; (size - 1) as usize];
-    r.read_exact(&mut buf)?;
-    let buf = &mut &buf[..];
+        // Event Payloads declaring Game Start (1 byte), Pre-Frame Update
+        // (4 bytes) and Game End (1 byte), preceded by the end of a game
+        // which was already in progress.
+        let payloads = [0x35, 10, 0x36, 0, 1, 0x37, 0, 4, 0x39, 0, 1];
+        let game = [payloads.as_slice(), &[0x36, 0, 0x37, 0, 0, 0, 0, 0x39, 0]].concat();
 
-    let mut sizes: PayloadSizes = HashMap::new();
-    for _ in (0..size - 1).step_by(3) {
-        let code = buf.read_u8()?;
-        let size = buf.read_u16::<BE>()?;
-        sizes.insert(code, size);
-    }
+        assert_eq!(writer.write(&[0x37, 0, 0]).unwrap(), 3);
+        for chunk in game.chunks(4) {
+            assert_eq!(writer.write(chunk).unwrap(), chunk.len());
+        }
+
+        assert!(writer.buffer.is_empty());
+        assert!(writer.current_file.is_none());
 
-    sizes
-        .get(&(Event::GameStart as u8))
-        .ok_or_else(|| Error::new(ErrorKind::Other, "missing Game Start in payload sizes"))?;
-    sizes
-        .get(&(Event::GameEnd as u8))
-        .ok_or_else(|| Error::new(ErrorKind::Other, "missing Game End in payload sizes"))?;
+        let files: Vec<PathBuf> = std::fs::read_dir(&spectate_directory_path).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 1);
+        let contents = std::fs::read(&files[0]).unwrap();
+        let raw_start = RAW_HEADER.len() + 4;
+        assert_eq!(&contents[raw_start..(raw_start + game.len())], game);
 
-    Ok((1 + size as usize, sizes)) // +1 byte for the event code
+        std::fs::remove_dir_all(&spectate_directory_path).unwrap();
+    }
 }
@@ -1,27 +1,210 @@
 use std::pin::Pin;
+use std::time::Duration;
 
+use futures::StreamExt;
+use tokio::net::TcpStream;
+use tokio::time::sleep;
 use tokio_tungstenite::tungstenite::client::IntoClientRequest;
-use tokio_tungstenite::connect_async;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
 use async_stream::stream;
 use tokio_tungstenite::tungstenite::Error;
 use tokio_tungstenite::tungstenite::Message;
+use url::Url;
 
-use crate::common::SlippiDataStream;
+use crate::common::{LatestGame, SlippiDataStream};
+
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(10);
+const MAX_RECONNECT_ATTEMPTS: u32 = 10;
+
+/// Everything received for the latest game, so that it can be skipped when a
+/// new connection sends the game so far again.
+#[derive(Debug, Default)]
+struct ReceivedGame {
+    game: LatestGame,
+    /// How much of `game` the current connection has sent again, while it is
+    /// catching up after a reconnect.
+    resent: Option<usize>,
+}
+
+impl ReceivedGame {
+    /// Expect the game so far to be sent again, by a new connection.
+    fn reconnected(&mut self) {
+        self.resent = Some(0);
+    }
+
+    /// Take data from the connection, returning the part which was not
+    /// already received.
+    fn receive(&mut self, data: Vec<u8>) -> Vec<u8> {
+        let new_data =
+            match self.resent {
+                None => data,
+                Some(resent) => {
+                    let expected = &self.game.data()[resent..];
+                    let matching = data.iter().zip(expected).take_while(|(a, b)| a == b).count();
+
+                    if matching == data.len() && matching < expected.len() {
+                        self.resent = Some(resent + matching);
+                        return Vec::new();
+                    }
+
+                    self.resent = None;
+                    if matching == expected.len() {
+                        tracing::info!("Caught up with the stream.");
+                        data[matching..].to_vec()
+                    } else {
+                        // The stream has moved on to another game, or did not
+                        // send the game so far. Continue from the new data.
+                        tracing::info!("Stream data changed while reconnecting, continuing from the new data.");
+                        [&self.game.data()[..resent], &data].concat()
+                    }
+                }
+            };
+
+        self.game.push(&new_data);
+        new_data
+    }
+}
+
+async fn connect(address: &str) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, Error> {
+    let request = address.into_client_request()?;
+    let (stream, _response) = connect_async(request).await?;
+    Ok(stream)
+}
+
+/// The address to reconnect to. The game so far must be sent again for the
+/// stream to continue where it left off, so a full replay is always asked for.
+fn reconnect_address(address: &str) -> String {
+    let Ok(mut url) = Url::parse(address) else {
+        return address.to_string();
+    };
+
+    let other_pairs: Vec<(String, String)> = url.query_pairs()
+        .filter(|(key, _)| key != "full_replay")
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(other_pairs)
+        .append_pair("full_replay", "true");
+
+    url.to_string()
+}
+
+/// Re-establish a connection, retrying with backoff. Gives up after
+/// `MAX_RECONNECT_ATTEMPTS`, or as soon as the server refuses the request,
+/// since retrying will not change its answer.
+async fn reconnect(address: &str) -> Option<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+    let mut delay = Duration::from_secs(1);
+
+    for attempt in 1..=MAX_RECONNECT_ATTEMPTS {
+        tracing::info!("Reconnecting to {} in {:?} (attempt {} of {})...", address, delay, attempt, MAX_RECONNECT_ATTEMPTS);
+        sleep(delay).await;
+
+        match connect(address).await {
+            Ok(stream) => {
+                tracing::info!("Reconnected to {}.", address);
+                return Some(stream);
+            }
+            Err(Error::Http(response)) if response.status().is_client_error() => {
+                tracing::warn!("{} refused to reconnect: {}", address, response.status());
+                return None;
+            }
+            Err(e) => {
+                tracing::warn!("Failed to reconnect to {}: {}", address, e);
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+        }
+    }
+
+    tracing::warn!("Giving up reconnecting to {}.", address);
+    None
+}
 
 // TODO: disconnect between socketaddr and &str between here and broadcast
 // Does it matter? These functions shouldn't really be interchangable anyways,
 // maybe just the return type matters. In that case, change name too.
+/// Connect to a stream. If the connection is lost, it is re-established, and
+/// the data of the game in progress which the source sends again is skipped,
+/// so that the stream continues where it left off. This relies on the source
+/// sending the game so far upon connection, as SpectatorMode does with
+/// `full_replay=true`, which is always asked for when reconnecting. The stream
+/// ends once the source closes the connection, or reconnecting fails.
 pub async fn data_stream(address: &str) -> Result<Pin<Box<SlippiDataStream>>, Error> {
     tracing::info!("Connecting to SpectatorMode at {}...", address);
-    let request = address.into_client_request()?;
-    let (stream, _response) = connect_async(request).await?;
+    let mut connection = connect(address).await?;
     tracing::info!("Connected to SpectatorMode.");
 
+    let address = reconnect_address(address);
+
     Ok(Box::pin(stream! {
-        for await result in stream {
-            if let Ok(Message::Binary(bytes)) = result {
-                yield bytes.to_vec();
+        let mut received = ReceivedGame::default();
+
+        loop {
+            while let Some(result) = connection.next().await {
+                match result {
+                    Ok(Message::Binary(bytes)) => {
+                        let data = received.receive(bytes.to_vec());
+                        if !data.is_empty() {
+                            yield data;
+                        }
+                    }
+                    Ok(Message::Close(_)) => return,
+                    Ok(_) => (),
+                    Err(e) => {
+                        tracing::warn!("Lost connection to {}: {}", address, e);
+                        break;
+                    }
+                }
+            }
+
+            match reconnect(&address).await {
+                Some(new_connection) => connection = new_connection,
+                None => return
             }
+            received.reconnected();
         }
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reconnect_address_asks_for_full_replay() {
+        assert_eq!(
+            reconnect_address("wss://spectatormode.tv/viewer_socket/websocket?stream_id=5"),
+            "wss://spectatormode.tv/viewer_socket/websocket?stream_id=5&full_replay=true"
+        );
+        assert_eq!(
+            reconnect_address("ws://localhost:4000/viewer_socket/websocket?full_replay=false&stream_id=5"),
+            "ws://localhost:4000/viewer_socket/websocket?stream_id=5&full_replay=true"
+        );
+        assert_eq!(reconnect_address("ws://localhost:4000/"), "ws://localhost:4000/?full_replay=true");
+    }
+
+    #[test]
+    fn received_game_skips_resent_data() {
+        // Event Payloads declaring Game Start (1 byte), Pre-Frame Update
+        // (4 bytes) and Game End (1 byte)
+        let payloads: [u8; 11] = [0x35, 10, 0x36, 0, 1, 0x37, 0, 4, 0x39, 0, 1];
+        let game = [payloads.as_slice(), &[0x36, 0, 0x37, 1, 1, 1, 1, 0x37, 2, 2, 2, 2]].concat();
+        let mut received = ReceivedGame::default();
+
+        assert_eq!(received.receive(game[..14].to_vec()), game[..14]);
+
+        // The game so far is sent again, split differently, followed by new data
+        received.reconnected();
+        assert_eq!(received.receive(game[..5].to_vec()), Vec::<u8>::new());
+        assert_eq!(received.receive(game[5..].to_vec()), game[14..]);
+        assert_eq!(received.receive(vec![0x39, 0]), vec![0x39, 0]);
+
+        // A new game started while disconnected
+        let next_game = [payloads.as_slice(), &[0x36, 1]].concat();
+        received.reconnected();
+        assert_eq!(received.receive(next_game[..4].to_vec()), Vec::<u8>::new());
+        assert_eq!(received.receive(next_game[4..].to_vec()), next_game);
+        assert_eq!(received.game.data(), next_game);
+    }
+}
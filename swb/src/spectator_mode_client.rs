@@ -1,17 +1,25 @@
 use async_trait::async_trait;
 use ezsockets::client::ClientCloseMode;
-use ezsockets::{Bytes, ClientConfig, SendError, SocketConfig};
+use ezsockets::{ClientConfig, SendError, SocketConfig};
 use futures::{
     Sink,
     task::{Context, Poll},
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
 use std::pin::Pin;
 use std::time::Duration;
 use thiserror::Error;
-use tokio::sync::oneshot;
+use tokio::sync::{oneshot, watch};
 use url::Url;
 
+use crate::{broadcast::connection_manager::create_packet, common::LatestGame};
+
+/// How much game data may be held while reconnecting to SpectatorMode. Past
+/// this, the games in progress are sent again from the start instead, once
+/// reconnected.
+const MAX_PENDING_BYTES: usize = 16 * 1024 * 1024;
+
 #[derive(Error, Debug)]
 pub enum SpectatorModeClientError {
     #[error("Send error: {0}")]
@@ -23,6 +31,9 @@ pub enum SpectatorModeClientError {
     #[error("Unable to connect: {0}")]
     ConnectError(&'static str),
 
+    #[error("Invalid SpectatorMode address: {0}")]
+    URLParseError(#[from] url::ParseError),
+
     #[error("Connection task panicked")]
     ConnectionTaskPanickedError,
 
@@ -35,8 +46,87 @@ pub enum SpectatorModeClientError {
 
 pub struct MyClient {
     handle: ezsockets::Client<Self>,
-    connected_sender: Option<oneshot::Sender<BridgeInfo>>,
-    initially_connected: bool,
+    /// The latest bridge info received, which is replaced if SpectatorMode
+    /// sends new info after reconnecting.
+    bridge_info: watch::Sender<Option<BridgeInfo>>,
+    /// The stream IDs first assigned, which game data is keyed by.
+    initial_stream_ids: Vec<u32>,
+    /// Whether game data can be sent, which is once bridge info has been
+    /// received on the current connection.
+    ready: bool,
+    outgoing: OutgoingBuffer,
+}
+
+/// Game data kept to be sent once SpectatorMode is reconnected, keyed by
+/// initial stream ID.
+#[derive(Debug, Default)]
+struct OutgoingBuffer {
+    /// Data of each stream since its latest game started.
+    games: BTreeMap<u32, LatestGame>,
+    /// Data produced while disconnected, in order.
+    pending: VecDeque<(u32, Vec<u8>)>,
+    pending_bytes: usize,
+    /// Set when `pending` overflowed, in which case it is no longer kept.
+    overflowed: bool,
+}
+
+impl OutgoingBuffer {
+    /// Keep data to be sent later, if needed.
+    fn push(&mut self, stream_id: u32, data: Vec<u8>, sent: bool) {
+        self.games.entry(stream_id).or_default().push(&data);
+
+        if sent || self.overflowed {
+            return;
+        }
+
+        if self.pending_bytes + data.len() > MAX_PENDING_BYTES {
+            tracing::warn!("Too much game data held while reconnecting, games in progress will be sent again from the start");
+            self.pending.clear();
+            self.pending_bytes = 0;
+            self.overflowed = true;
+        } else {
+            self.pending_bytes += data.len();
+            self.pending.push_back((stream_id, data));
+        }
+    }
+
+    /// Take the data to send after reconnecting. If the bridge was resumed,
+    /// this is what was produced while disconnected. Otherwise, or if that
+    /// overflowed, SpectatorMode no longer has the games in progress, so they
+    /// are sent again from the start.
+    fn take(&mut self, resumed: bool) -> Vec<(u32, Vec<u8>)> {
+        let pending = std::mem::take(&mut self.pending);
+        let overflowed = std::mem::take(&mut self.overflowed);
+        self.pending_bytes = 0;
+
+        if resumed && !overflowed {
+            pending.into()
+        } else {
+            self.games.iter()
+                .filter(|(_, game)| !game.data().is_empty())
+                .map(|(stream_id, game)| (*stream_id, game.data().to_vec()))
+                .collect()
+        }
+    }
+}
+
+impl MyClient {
+    fn send(&self, stream_id: u32, data: Vec<u8>) -> Result<(), ezsockets::Error> {
+        let current_stream_ids = self.bridge_info.borrow().as_ref().map(|info| info.stream_ids.clone()).unwrap_or_default();
+        let packet = create_packet(current_stream_id(stream_id, &self.initial_stream_ids, &current_stream_ids), data);
+        self.handle.binary(packet).map_err(|_| "SpectatorMode connection is closed")?;
+        Ok(())
+    }
+}
+
+/// The stream ID to send data under, for a stream which was assigned
+/// `stream_id` when the bridge first connected. SpectatorMode may assign new
+/// stream IDs after a reconnect, in the same order.
+fn current_stream_id(stream_id: u32, initial_stream_ids: &[u32], current_stream_ids: &[u32]) -> u32 {
+    initial_stream_ids.iter()
+        .position(|&initial| initial == stream_id)
+        .and_then(|i| current_stream_ids.get(i).copied())
+        .unwrap_or(stream_id)
 }
 
 pub struct SpectatorModeClient {
@@ -63,13 +153,18 @@ impl ConnectionMonitor {
 }
 
 pub enum Call {
-    GameData(Bytes),
+    /// Data for the stream with this initial stream ID.
+    GameData(u32, Vec<u8>),
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BridgeInfo {
     pub bridge_id: String,
     pub stream_ids: Vec<u32>,
+    /// Passed as the `reconnect_token` query parameter when reconnecting, to
+    /// resume the same bridge and keep its stream IDs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reconnect_token: Option<String>,
 }
 
 #[async_trait]
@@ -78,12 +173,37 @@ impl ezsockets::ClientExt for MyClient {
 
     async fn on_text(&mut self, text: ezsockets::Utf8Bytes) -> Result<(), ezsockets::Error> {
         let bridge_info = serde_json::from_str::<BridgeInfo>(text.as_str())?;
-        if !self.initially_connected {
-            if let Some(sender) = self.connected_sender.take() {
-                let _ = sender.send(bridge_info); // Ignore send errors (receiver might be dropped)
+        let resumed = self.bridge_info.borrow().as_ref().map(|previous| previous.stream_ids == bridge_info.stream_ids);
+
+        match resumed {
+            None => self.initial_stream_ids = bridge_info.stream_ids.clone(),
+            Some(true) => tracing::info!("Resumed bridge {} on SpectatorMode.", bridge_info.bridge_id),
+            Some(false) => {
+                tracing::warn!(
+                    "SpectatorMode assigned bridge ID {} and stream IDs {:?} after reconnecting, in place of {:?}",
+                    bridge_info.bridge_id,
+                    bridge_info.stream_ids,
+                    self.bridge_info.borrow().as_ref().unwrap().stream_ids
+                );
+            }
+        }
+
+        if let Some(reconnect_token) = &bridge_info.reconnect_token {
+            self.handle.set_reconnect_query_parameter("reconnect_token", reconnect_token)
+                .map_err(|_| "SpectatorMode connection is closed")?;
+        }
+
+        // Receivers are only dropped once the client is done
+        self.bridge_info.send_replace(Some(bridge_info));
+
+        if !self.ready {
+            self.ready = true;
+
+            for (stream_id, data) in self.outgoing.take(resumed.unwrap_or(true)) {
+                self.send(stream_id, data)?;
             }
-            self.initially_connected = true;
         }
+
         Ok(())
     }
 
@@ -93,33 +213,40 @@ impl ezsockets::ClientExt for MyClient {
 
     async fn on_call(&mut self, call: Self::Call) -> Result<(), ezsockets::Error> {
         match call {
-            Call::GameData(payload) => {
-                self.handle.binary(payload).unwrap();
+            Call::GameData(stream_id, data) => {
+                if self.ready {
+                    self.send(stream_id, data.clone())?;
+                }
+                self.outgoing.push(stream_id, data, self.ready);
             }
         };
         Ok(())
     }
 
-    // async fn on_close(&mut self, _close_frame: std::option::Option<ezsockets::CloseFrame>) -> Result<ClientCloseMode, Error> {
-    //     Ok(ClientCloseMode::Close)
-    // }
+    async fn on_close(&mut self, _close_frame: Option<ezsockets::CloseFrame>) -> Result<ClientCloseMode, ezsockets::Error> {
+        self.ready = false;
+        Ok(ClientCloseMode::Reconnect)
+    }
 
     async fn on_disconnect(&mut self) -> Result<ClientCloseMode, ezsockets::Error> {
+        self.ready = false;
         tracing::info!("Reconnecting to SpectatorMode...");
         // Ok(ClientCloseMode::Close)
         Ok(ClientCloseMode::Reconnect)
     }
 }
 
-impl Sink<Bytes> for SpectatorModeClient {
+/// Accepts game data keyed by the stream ID first assigned to its stream.
+/// Data is held while reconnecting, and sent under the current stream ID.
+impl Sink<(u32, Vec<u8>)> for SpectatorModeClient {
     type Error = SpectatorModeClientError;
 
     fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         Poll::Ready(Ok(()))
     }
 
-    fn start_send(self: Pin<&mut Self>, item: Bytes) -> Result<(), Self::Error> {
-        self.ws_client.call(Call::GameData(item))?;
+    fn start_send(self: Pin<&mut Self>, (stream_id, data): (u32, Vec<u8>)) -> Result<(), Self::Error> {
+        self.ws_client.call(Call::GameData(stream_id, data))?;
         Ok(())
     }
 
@@ -143,7 +270,7 @@ pub async fn initiate_spectatormode_connection(
     stream_count: usize,
 ) -> Result<(SpectatorModeClient, ConnectionMonitor, BridgeInfo), SpectatorModeClientError> {
     tracing::info!("Connecting to SpectatorMode...");
-    let url = Url::parse(address).unwrap();
+    let url = Url::parse(address)?;
     let mut socket_config = SocketConfig::default();
     socket_config.timeout = Duration::from_secs(15);
 
@@ -153,7 +280,7 @@ pub async fn initiate_spectatormode_connection(
         .max_reconnect_attempts(3)
         .query_parameter("stream_count", stream_count.to_string().as_str());
 
-    let (connected_sender, connected_receiver) = oneshot::channel::<BridgeInfo>();
+    let (bridge_info_sender, mut bridge_info_receiver) = watch::channel(None);
     let (handle_tx, handle_rx) = oneshot::channel();
 
     // Initiate the connection and await its completion within a background task.
@@ -164,8 +291,10 @@ pub async fn initiate_spectatormode_connection(
         let (sm_handle, sm_future) = ezsockets::connect(
             |handle| MyClient {
                 handle,
-                connected_sender: Some(connected_sender),
-                initially_connected: false,
+                bridge_info: bridge_info_sender,
+                initial_stream_ids: Vec::new(),
+                ready: false,
+                outgoing: OutgoingBuffer::default(),
             },
             config,
         )
@@ -181,13 +310,16 @@ pub async fn initiate_spectatormode_connection(
     let sm_handle = handle_rx.await.unwrap();
 
     // Wait for bridge info on successful connection
-    // TODO: When this fails, it's because connected_sender is dropped, which
-    // means connection_task finished before bridge info was received, meaning
-    // the connection never finished. Therefore, would like to return error
-    // from sm_future, but it isn't accessible here.
-    let bridge_info = connected_receiver
+    // TODO: When this fails, it's because the bridge info sender is dropped,
+    // which means connection_task finished before bridge info was received,
+    // meaning the connection never finished. Therefore, would like to return
+    // error from sm_future, but it isn't accessible here.
+    let bridge_info = bridge_info_receiver
+        .wait_for(Option::is_some)
         .await
-        .map_err(|_| SpectatorModeClientError::ConnectError("Failed to receive bridge info"))?;
+        .map_err(|_| SpectatorModeClientError::ConnectError("Failed to receive bridge info"))?
+        .clone()
+        .unwrap();
 
     tracing::info!(
         "Connected to SpectatorMode with bridge ID {} and stream IDs {:?}",
@@ -207,3 +339,35 @@ pub async fn initiate_spectatormode_connection(
         bridge_info,
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn current_stream_id_follows_reassigned_ids() {
+        assert_eq!(current_stream_id(5, &[4, 5], &[4, 5]), 5);
+        assert_eq!(current_stream_id(5, &[4, 5], &[10, 11]), 11);
+        assert_eq!(current_stream_id(7, &[4, 5], &[10, 11]), 7);
+    }
+
+    #[test]
+    fn outgoing_buffer_resends_games_unless_resumed() {
+        // Event Payloads declaring Game Start (1 byte) and Game End (1 byte)
+        let payloads = [0x35, 7, 0x36, 0, 1, 0x39, 0, 1];
+        let mut outgoing = OutgoingBuffer::default();
+
+        outgoing.push(1, payloads.to_vec(), true);
+        outgoing.push(1, vec![0x36, 0], true);
+        outgoing.push(2, payloads.to_vec(), true);
+        outgoing.push(1, vec![0x36, 1], false);
+        assert_eq!(outgoing.take(true), vec![(1, vec![0x36, 1])]);
+
+        // The game on stream 1 ends and another starts while disconnected, so
+        // only the new game is sent again
+        outgoing.push(1, [&[0x39, 0], payloads.as_slice(), &[0x36, 2]].concat(), false);
+        let game = [payloads.as_slice(), &[0x36, 2]].concat();
+        assert_eq!(outgoing.take(false), vec![(1, game), (2, payloads.to_vec())]);
+        assert!(outgoing.pending.is_empty());
+    }
+}
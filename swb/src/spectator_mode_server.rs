@@ -0,0 +1,525 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration
+};
+
+use futures::{SinkExt, StreamExt};
+use rand::{distr::Alphanumeric, Rng};
+use thiserror::Error;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::broadcast::{self, error::RecvError},
+    task::JoinSet
+};
+use tokio_tungstenite::{
+    accept_async,
+    accept_hdr_async,
+    tungstenite::{
+        self,
+        handshake::server::{ErrorResponse, Request, Response},
+        http::StatusCode,
+        Message
+    },
+    WebSocketStream
+};
+use url::Url;
+
+use crate::{
+    broadcast::connection_manager::parse_packets,
+    common::{LatestGame, SlippiDataStream},
+    spectator_mode_client::BridgeInfo
+};
+
+#[derive(Error, Debug)]
+pub enum SpectatorModeServerError {
+    #[error("Unable to listen for connections: {0}")]
+    BindError(std::io::Error),
+
+    #[error("WebSocket error: {0}")]
+    WebSocketError(#[from] tungstenite::Error),
+
+    #[error("Unable to encode bridge info: {0}")]
+    EncodeError(#[from] serde_json::Error),
+
+    #[error("Unknown stream ID: {0}")]
+    UnknownStream(u32),
+}
+
+/// How many messages a viewer may fall behind a stream before it is
+/// disconnected.
+const VIEWER_BUFFER_SIZE: usize = 1024;
+
+/// The most streams one bridge may broadcast.
+const MAX_STREAMS_PER_BRIDGE: usize = 16;
+
+/// How long the streams of a bridge which lost its connection are kept, for it
+/// to reconnect and resume them.
+const BRIDGE_RECONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The endpoint a client requested when opening a WebSocket connection, which
+/// follows the paths and query parameters used by SpectatorMode.
+#[derive(Debug, PartialEq)]
+enum Endpoint {
+    /// `/bridge_socket/websocket?stream_count=N`, or with `reconnect_token=T`
+    /// to resume a bridge which lost its connection.
+    Bridge { stream_count: usize, reconnect_token: Option<String> },
+    /// `/viewer_socket/websocket?stream_id=N&full_replay=true`
+    Viewer { stream_id: u32, full_replay: bool },
+}
+
+fn parse_endpoint(uri: &str) -> Result<Endpoint, String> {
+    let url = Url::parse(&format!("ws://localhost{}", uri)).map_err(|e| e.to_string())?;
+    let query_param = |name: &str| url.query_pairs().find(|(key, _)| key == name).map(|(_, value)| value.into_owned());
+
+    match url.path() {
+        "/bridge_socket/websocket" => {
+            let stream_count =
+                match query_param("stream_count") {
+                    Some(value) => {
+                        match usize::from_str(&value) {
+                            Ok(stream_count) if (1..=MAX_STREAMS_PER_BRIDGE).contains(&stream_count) => stream_count,
+                            _ => return Err(format!("invalid stream_count: {}", value))
+                        }
+                    }
+                    None => 1
+                };
+            Ok(Endpoint::Bridge { stream_count, reconnect_token: query_param("reconnect_token") })
+        }
+        "/viewer_socket/websocket" => {
+            let value = query_param("stream_id").ok_or("missing stream_id")?;
+            let stream_id = u32::from_str(&value).map_err(|_| format!("invalid stream_id: {}", value))?;
+            let full_replay = query_param("full_replay").is_some_and(|value| value == "true");
+            Ok(Endpoint::Viewer { stream_id, full_replay })
+        }
+        other_path => Err(format!("unknown path: {}", other_path))
+    }
+}
+
+/// A stream being broadcast by a bridge.
+struct LiveStream {
+    /// Everything received for the latest game, for viewers who join partway
+    /// through.
+    replay: LatestGame,
+    sender: broadcast::Sender<Vec<u8>>,
+}
+
+impl LiveStream {
+    fn new() -> LiveStream {
+        LiveStream {
+            replay: LatestGame::default(),
+            sender: broadcast::channel(VIEWER_BUFFER_SIZE).0,
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        self.replay.push(data);
+
+        // Sending only fails when there are no viewers
+        let _ = self.sender.send(data.to_vec());
+    }
+
+    /// Start receiving live data, along with the replay so far if requested.
+    /// Both are taken at once so that no data is missed or repeated in
+    /// between.
+    fn subscribe(&self, full_replay: bool) -> (Vec<u8>, broadcast::Receiver<Vec<u8>>) {
+        let replay = if full_replay { self.replay.data().to_vec() } else { Vec::new() };
+        (replay, self.sender.subscribe())
+    }
+}
+
+/// A bridge, which is kept for a while after losing its connection.
+struct Bridge {
+    stream_ids: Vec<u32>,
+    reconnect_token: String,
+    /// Identifies the bridge's latest connection, so that an earlier one
+    /// ending does not end the bridge.
+    connection: u64,
+}
+
+#[derive(Default)]
+struct ServerState {
+    streams: HashMap<u32, LiveStream>,
+    bridges: HashMap<String, Bridge>,
+    last_stream_id: u32,
+    last_connection: u64,
+}
+
+type SharedState = Arc<Mutex<ServerState>>;
+
+fn random_string(length: usize) -> String {
+    rand::rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+impl ServerState {
+    /// Connect a bridge, resuming the bridge `reconnect_token` was issued for
+    /// if it is still kept. Returns its info, and the ID of the connection.
+    fn connect_bridge(&mut self, stream_count: usize, reconnect_token: Option<&str>) -> (BridgeInfo, u64) {
+        self.last_connection += 1;
+        let connection = self.last_connection;
+
+        let resumed = reconnect_token.and_then(|reconnect_token| {
+            self.bridges.iter_mut().find(|(_, bridge)| bridge.reconnect_token == reconnect_token)
+        });
+
+        if let Some((bridge_id, bridge)) = resumed {
+            bridge.connection = connection;
+            let bridge_info = BridgeInfo {
+                bridge_id: bridge_id.clone(),
+                stream_ids: bridge.stream_ids.clone(),
+                reconnect_token: Some(bridge.reconnect_token.clone()),
+            };
+            return (bridge_info, connection);
+        }
+
+        let bridge_id = random_string(16);
+        let reconnect_token = random_string(32);
+
+        let stream_ids: Vec<u32> = (0..stream_count).map(|_| {
+            self.last_stream_id += 1;
+            self.streams.insert(self.last_stream_id, LiveStream::new());
+            self.last_stream_id
+        }).collect();
+
+        self.bridges.insert(bridge_id.clone(), Bridge { stream_ids: stream_ids.clone(), reconnect_token: reconnect_token.clone(), connection });
+        (BridgeInfo { bridge_id, stream_ids, reconnect_token: Some(reconnect_token) }, connection)
+    }
+
+    /// End a bridge and its streams, unless it has connected again since
+    /// `connection`.
+    fn end_bridge(&mut self, bridge_id: &str, connection: u64) {
+        if self.bridges.get(bridge_id).is_none_or(|bridge| bridge.connection != connection) {
+            return;
+        }
+
+        // Ending the streams disconnects their viewers
+        for stream_id in &self.bridges.remove(bridge_id).unwrap().stream_ids {
+            self.streams.remove(stream_id);
+        }
+        tracing::info!("Bridge {} disconnected", bridge_id);
+    }
+}
+
+async fn handle_bridge(
+    mut ws: WebSocketStream<TcpStream>,
+    stream_count: usize,
+    reconnect_token: Option<String>,
+    state: SharedState
+) -> Result<(), SpectatorModeServerError> {
+    let (bridge_info, connection) = state.lock().unwrap().connect_bridge(stream_count, reconnect_token.as_deref());
+
+    if reconnect_token.is_some() && bridge_info.reconnect_token == reconnect_token {
+        tracing::info!("Bridge {} reconnected", bridge_info.bridge_id);
+    } else {
+        tracing::info!("Bridge {} connected with stream IDs {:?}", bridge_info.bridge_id, bridge_info.stream_ids);
+    }
+
+    let mut closed = false;
+
+    let result = async {
+        ws.send(Message::text(serde_json::to_string(&bridge_info)?)).await?;
+
+        while let Some(message) = ws.next().await {
+            let message = message?;
+            closed |= matches!(message, Message::Close(_));
+
+            if let Message::Binary(bytes) = message {
+                let packets = match parse_packets(&bytes) {
+                    Ok(packets) => packets,
+                    Err(e) => {
+                        tracing::warn!("Invalid message from bridge {}: {}", bridge_info.bridge_id, e);
+                        continue;
+                    }
+                };
+
+                let mut state = state.lock().unwrap();
+                for (stream_id, data) in packets {
+                    match state.streams.get_mut(&stream_id) {
+                        Some(stream) if bridge_info.stream_ids.contains(&stream_id) => stream.push(data),
+                        _ => tracing::warn!("Bridge {} sent data for stream {} which it does not own", bridge_info.bridge_id, stream_id)
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }.await;
+
+    // A bridge which closed its connection is done, otherwise it may be back.
+    if closed {
+        state.lock().unwrap().end_bridge(&bridge_info.bridge_id, connection);
+    } else {
+        tracing::info!("Bridge {} lost its connection, keeping its streams for {:?}", bridge_info.bridge_id, BRIDGE_RECONNECT_TIMEOUT);
+
+        tokio::spawn(async move {
+            tokio::time::sleep(BRIDGE_RECONNECT_TIMEOUT).await;
+            state.lock().unwrap().end_bridge(&bridge_info.bridge_id, connection);
+        });
+    }
+
+    result
+}
+
+async fn handle_viewer(mut ws: WebSocketStream<TcpStream>, stream_id: u32, full_replay: bool, state: SharedState) -> Result<(), SpectatorModeServerError> {
+    let subscription = state.lock().unwrap().streams.get(&stream_id).map(|stream| stream.subscribe(full_replay));
+
+    let Some((replay, receiver)) = subscription else {
+        ws.close(None).await?;
+        return Err(SpectatorModeServerError::UnknownStream(stream_id));
+    };
+
+    tracing::info!("Viewer joined stream {}", stream_id);
+    let result = send_to_viewer(ws, replay, receiver).await;
+    tracing::info!("Viewer left stream {}", stream_id);
+
+    result
+}
+
+/// Send the replay so far to a viewer, followed by live data, until either the
+/// stream or the viewer's connection ends.
+async fn send_to_viewer(
+    mut ws: WebSocketStream<TcpStream>,
+    replay: Vec<u8>,
+    mut receiver: broadcast::Receiver<Vec<u8>>
+) -> Result<(), SpectatorModeServerError> {
+    if !replay.is_empty() {
+        ws.send(Message::binary(replay)).await?;
+    }
+
+    loop {
+        tokio::select! {
+            data = receiver.recv() => {
+                match data {
+                    Ok(data) => ws.send(Message::binary(data)).await?,
+                    Err(RecvError::Lagged(_)) => {
+                        // The viewer's replay would be missing data
+                        tracing::warn!("Viewer fell too far behind, disconnecting");
+                        break;
+                    }
+                    Err(RecvError::Closed) => break
+                }
+            }
+            message = ws.next() => {
+                match message {
+                    Some(Ok(_)) => (),
+                    Some(Err(e)) => return Err(e.into()),
+                    None => return Ok(())
+                }
+            }
+        }
+    }
+
+    ws.close(None).await?;
+    Ok(())
+}
+
+async fn handle_connection(tcp_stream: TcpStream, state: SharedState) -> Result<(), SpectatorModeServerError> {
+    let mut endpoint = None;
+
+    // The error response type is determined by tungstenite
+    #[allow(clippy::result_large_err)]
+    let select_endpoint = |request: &Request, response: Response| {
+        match parse_endpoint(&request.uri().to_string()) {
+            Ok(requested_endpoint) => {
+                endpoint = Some(requested_endpoint);
+                Ok(response)
+            }
+            Err(message) => {
+                let mut error_response = ErrorResponse::new(Some(message));
+                *error_response.status_mut() = StatusCode::NOT_FOUND;
+                Err(error_response)
+            }
+        }
+    };
+
+    let ws = accept_hdr_async(tcp_stream, select_endpoint).await?;
+
+    match endpoint {
+        Some(Endpoint::Bridge { stream_count, reconnect_token }) => handle_bridge(ws, stream_count, reconnect_token, state).await,
+        Some(Endpoint::Viewer { stream_id, full_replay }) => handle_viewer(ws, stream_id, full_replay, state).await,
+        None => Ok(())
+    }
+}
+
+/// Run a local server which bridges and viewers can connect to in place of
+/// SpectatorMode, until the process is stopped.
+pub async fn serve(addr: SocketAddr) -> Result<(), SpectatorModeServerError> {
+    let listener = TcpListener::bind(addr).await.map_err(SpectatorModeServerError::BindError)?;
+    let state = SharedState::default();
+    tracing::info!("Listening for bridges and viewers on {}", addr);
+
+    loop {
+        let (tcp_stream, peer_addr) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                tracing::warn!("Error accepting connection: {}", e);
+                continue;
+            }
+        };
+
+        tracing::debug!("Accepted connection from {}", peer_addr);
+        let state = Arc::clone(&state);
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(tcp_stream, state).await {
+                tracing::warn!("Connection from {} ended with error: {}", peer_addr, e);
+            }
+        });
+    }
+}
+
+/// Serve a single Slippi stream directly to viewers on the local network,
+/// without SpectatorMode. Viewers may connect on any path, and receive the
+/// replay of the latest game followed by live data, which is the format
+/// `swb spectate` accepts. Returns once the Slippi stream ends.
+pub async fn relay(mut slippi_stream: Pin<Box<SlippiDataStream>>, addr: SocketAddr) -> Result<(), SpectatorModeServerError> {
+    let listener = TcpListener::bind(addr).await.map_err(SpectatorModeServerError::BindError)?;
+    let mut live_stream = LiveStream::new();
+    let mut viewers = JoinSet::new();
+    tracing::info!("Relaying to viewers on {}", addr);
+
+    loop {
+        tokio::select! {
+            data = slippi_stream.next() => {
+                match data {
+                    Some(data) => live_stream.push(&data),
+                    None => break
+                }
+            }
+            connection = listener.accept() => {
+                match connection {
+                    Ok((tcp_stream, peer_addr)) => {
+                        // Data sent during the handshake waits in the receiver
+                        let (replay, receiver) = live_stream.subscribe(true);
+                        viewers.spawn(relay_to_viewer(tcp_stream, peer_addr, replay, receiver));
+                    }
+                    Err(e) => tracing::warn!("Error accepting connection: {}", e)
+                }
+            }
+            Some(_) = viewers.join_next(), if !viewers.is_empty() => ()
+        }
+    }
+
+    // Ending the stream disconnects viewers once they have received
+    // everything sent so far.
+    drop(live_stream);
+    while viewers.join_next().await.is_some() {}
+
+    Ok(())
+}
+
+async fn relay_to_viewer(tcp_stream: TcpStream, peer_addr: SocketAddr, replay: Vec<u8>, receiver: broadcast::Receiver<Vec<u8>>) {
+    let result = async {
+        let ws = accept_async(tcp_stream).await?;
+        tracing::info!("Viewer {} connected", peer_addr);
+        send_to_viewer(ws, replay, receiver).await
+    }.await;
+
+    match result {
+        Ok(()) => tracing::info!("Viewer {} disconnected", peer_addr),
+        Err(e) => tracing::warn!("Viewer {} disconnected with error: {}", peer_addr, e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_tungstenite::{connect_async, MaybeTlsStream};
+    use crate::broadcast::connection_manager::create_packet;
+
+    type ClientStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    #[test]
+    fn parse_endpoint_reads_spectatormode_paths() {
+        assert_eq!(
+            parse_endpoint("/bridge_socket/websocket?stream_count=2"),
+            Ok(Endpoint::Bridge { stream_count: 2, reconnect_token: None })
+        );
+        assert_eq!(
+            parse_endpoint("/bridge_socket/websocket?stream_count=2&reconnect_token=abc"),
+            Ok(Endpoint::Bridge { stream_count: 2, reconnect_token: Some("abc".to_string()) })
+        );
+        assert!(parse_endpoint("/bridge_socket/websocket?stream_count=0").is_err());
+        assert!(parse_endpoint("/bridge_socket/websocket?stream_count=1000000").is_err());
+        assert_eq!(
+            parse_endpoint("/viewer_socket/websocket?stream_id=3&full_replay=true"),
+            Ok(Endpoint::Viewer { stream_id: 3, full_replay: true })
+        );
+        assert!(parse_endpoint("/viewer_socket/websocket").is_err());
+        assert!(parse_endpoint("/").is_err());
+    }
+
+    #[test]
+    fn live_stream_replay_starts_at_latest_game() {
+        // Event Payloads declaring Game Start (1 byte) and Game End (1 byte).
+        let payloads = [0x35, 7, 0x36, 0, 1, 0x39, 0, 1];
+        let mut stream = LiveStream::new();
+
+        stream.push(&payloads);
+        stream.push(&[0x39, 0, 0x35]);
+        assert_eq!(stream.replay.data(), [payloads.to_vec(), vec![0x39, 0, 0x35]].concat());
+
+        stream.push(&payloads[1..]);
+        assert_eq!(stream.replay.data(), payloads);
+    }
+
+    /// Connect to `serve` at `addr`, waiting for it to start listening.
+    async fn connect_to(addr: SocketAddr, path: &str) -> ClientStream {
+        loop {
+            match connect_async(format!("ws://{}{}", addr, path)).await {
+                Ok((ws, _)) => return ws,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await
+            }
+        }
+    }
+
+    async fn next_message(ws: &mut ClientStream) -> Message {
+        tokio::time::timeout(Duration::from_secs(5), ws.next()).await.unwrap().unwrap().unwrap()
+    }
+
+    async fn receive_bridge_info(ws: &mut ClientStream) -> BridgeInfo {
+        serde_json::from_str(next_message(ws).await.to_text().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn serve_resumes_bridges_with_reconnect_token() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        tokio::spawn(serve(addr));
+
+        let mut bridge = connect_to(addr, "/bridge_socket/websocket?stream_count=2").await;
+        let bridge_info = receive_bridge_info(&mut bridge).await;
+        let reconnect_token = bridge_info.reconnect_token.clone().expect("a reconnect token should be issued");
+        let stream_id = bridge_info.stream_ids[0];
+
+        let viewer_path = format!("/viewer_socket/websocket?stream_id={}&full_replay=true", stream_id);
+        bridge.send(Message::binary(create_packet(stream_id, vec![0x35, 1]))).await.unwrap();
+        let mut viewer = connect_to(addr, &viewer_path).await;
+        assert_eq!(next_message(&mut viewer).await, Message::binary(vec![0x35, 1]));
+
+        // Lose the connection without closing it, and resume with the token
+        drop(bridge);
+        let mut bridge = connect_to(addr, &format!("/bridge_socket/websocket?stream_count=2&reconnect_token={}", reconnect_token)).await;
+        let resumed_info = receive_bridge_info(&mut bridge).await;
+        assert_eq!(resumed_info.bridge_id, bridge_info.bridge_id);
+        assert_eq!(resumed_info.stream_ids, bridge_info.stream_ids);
+        assert_eq!(resumed_info.reconnect_token, Some(reconnect_token));
+
+        // Viewers stay on the stream while it is resumed
+        bridge.send(Message::binary(create_packet(stream_id, vec![0x36]))).await.unwrap();
+        assert_eq!(next_message(&mut viewer).await, Message::binary(vec![0x36]));
+
+        // An unknown token gets a new bridge
+        let mut other_bridge = connect_to(addr, "/bridge_socket/websocket?stream_count=1&reconnect_token=unknown").await;
+        let other_info = receive_bridge_info(&mut other_bridge).await;
+        assert_ne!(other_info.bridge_id, bridge_info.bridge_id);
+        assert_ne!(other_info.reconnect_token, bridge_info.reconnect_token);
+        assert_eq!(other_info.stream_ids.len(), 1);
+    }
+}
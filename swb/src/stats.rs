@@ -0,0 +1,343 @@
+//! Summary statistics of a game, computed from its events as it is played.
+
+use std::{collections::BTreeMap, fmt};
+
+use serde::Serialize;
+
+use crate::slippi::{self, GameEnd, GameEndMethod, GameStart, PostFrameUpdate, SlippiEvent};
+
+/// The first frame players can act on, after the countdown.
+const FIRST_PLAYABLE_FRAME: i32 = -39;
+
+/// How long after a hit a player must go without being hit again for the next
+/// hit to count as a new opening. Slippi's own conversion detection waits for
+/// 45 frames after the player leaves hitstun; since hitstun is not tracked
+/// here, this allows extra time for it.
+const OPENING_RESET_FRAMES: i32 = 90;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlayerSummary {
+    /// Port number, from 1 to 4.
+    pub port: u8,
+    /// External character ID.
+    pub character: u8,
+    pub character_name: Option<&'static str>,
+    pub display_name: Option<String>,
+    pub connect_code: Option<String>,
+    pub team: Option<u8>,
+    pub stocks_remaining: u8,
+    pub final_percent: f32,
+    /// Damage dealt to other players, as percent.
+    pub damage_dealt: f32,
+    /// Stocks taken from other players. Self-destructs are not counted.
+    pub kills: u32,
+    /// Strings of hits on another player, each of which ends once that
+    /// player goes a while without being hit or loses a stock.
+    pub openings: u32,
+    pub openings_per_kill: Option<f32>,
+    pub l_cancels_successful: u32,
+    pub l_cancels_attempted: u32,
+    pub l_cancel_rate: Option<f32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GameSummary {
+    pub stage: u16,
+    pub stage_name: Option<&'static str>,
+    pub end_method: GameEndMethod,
+    /// Ports of the winning players; more than one in teams, and none if the
+    /// game had no winner.
+    pub winners: Vec<u8>,
+    /// Frames from the end of the countdown to the end of the game.
+    pub duration_frames: u32,
+    pub duration_secs: f32,
+    pub players: Vec<PlayerSummary>,
+}
+
+impl fmt::Display for GameSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let minutes = self.duration_frames / 60 / 60;
+        let seconds = self.duration_frames / 60 % 60;
+        write!(f, "{}:{:02} on {}", minutes, seconds, self.stage_name.unwrap_or("unknown stage"))?;
+
+        if !self.winners.is_empty() {
+            let winners = self.winners.iter().map(|port| format!("P{}", port)).collect::<Vec<String>>();
+            write!(f, ", won by {}", winners.join(" and "))?;
+        }
+
+        for player in &self.players {
+            write!(f, "\n  P{} {}", player.port, player.character_name.unwrap_or("unknown character"))?;
+            if let Some(name) = player.connect_code.as_ref().or(player.display_name.as_ref()) {
+                write!(f, " ({})", name)?;
+            }
+            write!(
+                f,
+                ": {} stocks left, {:.1}% dealt, {} kills",
+                player.stocks_remaining, player.damage_dealt, player.kills
+            )?;
+            if let Some(openings_per_kill) = player.openings_per_kill {
+                write!(f, ", {:.1} openings per kill", openings_per_kill)?;
+            }
+            if let Some(l_cancel_rate) = player.l_cancel_rate {
+                write!(f, ", {:.0}% L-cancels", l_cancel_rate * 100.0)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct PlayerStats {
+    stocks: u8,
+    percent: f32,
+    damage_dealt: f32,
+    kills: u32,
+    openings: u32,
+    /// The frame this player last hit each other port, while that opening is
+    /// still going.
+    last_hits: BTreeMap<u8, i32>,
+    l_cancels_successful: u32,
+    l_cancels_attempted: u32,
+}
+
+/// Computes a [`GameSummary`] from the events of a game.
+#[derive(Debug, Default)]
+pub struct StatsTracker {
+    game_start: Option<GameStart>,
+    last_frame: i32,
+    players: BTreeMap<u8, PlayerStats>,
+}
+
+impl StatsTracker {
+    pub fn new() -> StatsTracker {
+        StatsTracker::default()
+    }
+
+    /// Update the statistics from an event. Returns the summary of the game
+    /// when it ends.
+    pub fn read_event(&mut self, event: &SlippiEvent) -> Option<GameSummary> {
+        match event {
+            SlippiEvent::GameStart(game_start) => {
+                self.last_frame = FIRST_PLAYABLE_FRAME;
+                self.players = game_start.players.iter()
+                    .map(|player| (player.port, PlayerStats { stocks: player.stocks, ..Default::default() }))
+                    .collect();
+                self.game_start = Some(game_start.clone());
+                None
+            }
+            SlippiEvent::PostFrameUpdate(post_frame) if !post_frame.is_follower => {
+                self.read_post_frame(post_frame);
+                None
+            }
+            SlippiEvent::GameEnd(game_end) => self.summarize(game_end),
+            _ => None
+        }
+    }
+
+    fn read_post_frame(&mut self, post_frame: &PostFrameUpdate) {
+        self.last_frame = self.last_frame.max(post_frame.frame);
+
+        let Some(player) = self.players.get_mut(&post_frame.port) else {
+            return;
+        };
+
+        let damage_taken = post_frame.percent - player.percent;
+        let lost_stock = post_frame.stocks < player.stocks;
+        player.percent = post_frame.percent;
+        player.stocks = post_frame.stocks;
+
+        match post_frame.l_cancel_status {
+            Some(1) => {
+                player.l_cancels_successful += 1;
+                player.l_cancels_attempted += 1;
+            }
+            Some(2) => player.l_cancels_attempted += 1,
+            _ => ()
+        }
+
+        let victim_port = post_frame.port;
+        let attacker_port = post_frame.last_hit_by;
+
+        if attacker_port != victim_port && let Some(attacker) = self.players.get_mut(&attacker_port) {
+            if damage_taken > 0.0 {
+                attacker.damage_dealt += damage_taken;
+
+                let new_opening = attacker.last_hits.get(&victim_port)
+                    .is_none_or(|&last_hit| post_frame.frame - last_hit > OPENING_RESET_FRAMES);
+                if new_opening {
+                    attacker.openings += 1;
+                }
+                attacker.last_hits.insert(victim_port, post_frame.frame);
+            }
+
+            if lost_stock {
+                attacker.kills += 1;
+            }
+        }
+
+        if lost_stock {
+            for player in self.players.values_mut() {
+                player.last_hits.remove(&victim_port);
+            }
+        }
+    }
+
+    fn summarize(&self, game_end: &GameEnd) -> Option<GameSummary> {
+        let game_start = self.game_start.as_ref()?;
+
+        let players: Vec<PlayerSummary> = game_start.players.iter().map(|player| {
+            let stats = &self.players[&player.port];
+
+            PlayerSummary {
+                port: player.port + 1,
+                character: player.character,
+                character_name: slippi::character_name(player.character),
+                display_name: player.display_name.clone(),
+                connect_code: player.connect_code.clone(),
+                team: player.team,
+                stocks_remaining: stats.stocks,
+                final_percent: stats.percent,
+                damage_dealt: stats.damage_dealt,
+                kills: stats.kills,
+                openings: stats.openings,
+                openings_per_kill: (stats.kills > 0).then(|| stats.openings as f32 / stats.kills as f32),
+                l_cancels_successful: stats.l_cancels_successful,
+                l_cancels_attempted: stats.l_cancels_attempted,
+                l_cancel_rate: (stats.l_cancels_attempted > 0)
+                    .then(|| stats.l_cancels_successful as f32 / stats.l_cancels_attempted as f32),
+            }
+        }).collect();
+
+        let duration_frames = (self.last_frame - FIRST_PLAYABLE_FRAME).max(0) as u32;
+
+        Some(GameSummary {
+            stage: game_start.stage,
+            stage_name: slippi::stage_name(game_start.stage),
+            end_method: game_end.method,
+            winners: winners(&players, game_end),
+            duration_frames,
+            duration_secs: duration_frames as f32 / 60.0,
+            players,
+        })
+    }
+}
+
+/// The ports of the winning players. Placements are used when the game has
+/// them; otherwise, the player with the most stocks and then the least
+/// percent wins, along with their team.
+fn winners(players: &[PlayerSummary], game_end: &GameEnd) -> Vec<u8> {
+    let winner =
+        if let Some(placements) = game_end.placements {
+            players.iter().find(|player| placements[(player.port - 1) as usize] == 0)
+        } else if let Some(initiator) = game_end.lras_initiator {
+            // Whoever quits out forfeits, which only decides a 1v1
+            match players {
+                [a, b] => Some(if a.port == initiator + 1 { b } else { a }),
+                _ => None
+            }
+        } else if game_end.method == GameEndMethod::NoContest {
+            None
+        } else {
+            players.iter().min_by(|a, b| {
+                b.stocks_remaining.cmp(&a.stocks_remaining).then(a.final_percent.total_cmp(&b.final_percent))
+            })
+        };
+
+    match winner {
+        Some(winner) if winner.team.is_some() => {
+            players.iter().filter(|player| player.team == winner.team).map(|player| player.port).collect()
+        }
+        Some(winner) => vec![winner.port],
+        None => vec![]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::slippi::{Player, PlayerType};
+
+    fn post_frame(frame: i32, port: u8, percent: f32, stocks: u8, last_hit_by: u8, l_cancel_status: u8) -> SlippiEvent {
+        SlippiEvent::PostFrameUpdate(PostFrameUpdate {
+            frame,
+            port,
+            is_follower: false,
+            internal_character: 0,
+            action_state: 0,
+            x: 0.0,
+            y: 0.0,
+            facing_direction: 1.0,
+            percent,
+            shield_size: 60.0,
+            last_attack_landed: 0,
+            combo_count: 0,
+            last_hit_by,
+            stocks,
+            action_state_frame: Some(0.0),
+            is_airborne: Some(false),
+            jumps_remaining: Some(1),
+            l_cancel_status: Some(l_cancel_status),
+        })
+    }
+
+    #[test]
+    fn stats_tracker_summarizes_game() {
+        let player = |port: u8, character: u8| Player {
+            port,
+            character,
+            player_type: PlayerType::Human,
+            stocks: 2,
+            costume: 0,
+            team: None,
+            display_name: None,
+            connect_code: None,
+        };
+        let mut tracker = StatsTracker::new();
+
+        tracker.read_event(&SlippiEvent::GameStart(GameStart {
+            version: (3, 12, 0),
+            is_teams: false,
+            stage: 32,
+            players: vec![player(0, 2), player(3, 20)],
+            random_seed: 0,
+            is_pal: Some(false),
+            is_frozen_ps: Some(false),
+        }));
+
+        let events = [
+            // P1 opens up P4 twice in a row, then again later
+            post_frame(0, 3, 10.0, 2, 0, 0),
+            post_frame(10, 3, 25.0, 2, 0, 0),
+            post_frame(200, 3, 40.0, 2, 0, 0),
+            // P4 lands two aerials, one L-cancelled, and then loses a stock
+            post_frame(201, 3, 40.0, 2, 0, 1),
+            post_frame(220, 3, 40.0, 2, 0, 2),
+            post_frame(230, 3, 0.0, 1, 0, 0),
+            // P1 self-destructs
+            post_frame(300, 0, 0.0, 1, 6, 0),
+            post_frame(340, 3, 12.0, 1, 0, 0),
+        ];
+        for event in &events {
+            assert_eq!(tracker.read_event(event), None);
+        }
+
+        let game_end = GameEnd { method: GameEndMethod::Time, lras_initiator: None, placements: None };
+        let summary = tracker.read_event(&SlippiEvent::GameEnd(game_end)).unwrap();
+
+        assert_eq!(summary.stage_name, Some("FinalDestination"));
+        assert_eq!(summary.duration_frames, 379);
+        assert_eq!(summary.winners, vec![1]);
+
+        let p1 = &summary.players[0];
+        assert_eq!((p1.port, p1.stocks_remaining, p1.kills, p1.openings), (1, 1, 1, 3));
+        assert_eq!(p1.damage_dealt, 52.0);
+        assert_eq!(p1.openings_per_kill, Some(3.0));
+        assert_eq!(p1.l_cancel_rate, None);
+
+        let p4 = &summary.players[1];
+        assert_eq!((p4.port, p4.stocks_remaining, p4.kills, p4.final_percent), (4, 1, 0, 12.0));
+        assert_eq!(p4.openings_per_kill, None);
+        assert_eq!(p4.l_cancel_rate, Some(0.5));
+    }
+}